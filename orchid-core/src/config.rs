@@ -0,0 +1,293 @@
+use std::{collections::HashMap, env, fmt::Display, path::PathBuf, str::FromStr, time::Duration};
+
+use crate::{
+    err::{OrchidError, OrchidResult},
+    twitch::chat::source::{
+        irc::{IrcEndpoint, TWITCH_IRC_HOST, TWITCH_IRC_PORT, TWITCH_IRC_TLS_PORT},
+        replay::ReplaySpeed,
+    },
+    twitch::chat::{
+        color::{ColorSettings, HexColor},
+        history::RetentionPolicy,
+        processor::ProcessorKind,
+    },
+    twitch::emote::{
+        bttv::BTTV_API_URL,
+        cache::EmoteTtls,
+        ffz::FFZ_CDN_URL,
+        images::EmoteCdns,
+        index::{default_precedence, EmoteProvider, EmoteSource},
+        seventv::{SEVENTV_API_URL, SEVENTV_CDN_URL},
+    },
+};
+
+/// Where chat comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatSourceKind {
+    /// Twitch IRC
+    Twitch,
+    /// Generated offline chat, for overlay development
+    Mock,
+    /// A recorded IRC session
+    Replay,
+}
+
+impl FromStr for ChatSourceKind {
+    type Err = OrchidError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "twitch" => Ok(ChatSourceKind::Twitch),
+            "mock" => Ok(ChatSourceKind::Mock),
+            "replay" => Ok(ChatSourceKind::Replay),
+            _ => Err(OrchidError::ConfigError(format!(
+                "Unknown chat source: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Server configuration, read from `ORCHID_*` environment variables.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// `ORCHID_CHAT_SOURCE`: `twitch` (default), `mock` or `replay`
+    pub chat_source: ChatSourceKind,
+    /// IRC server for the `twitch` source.
+    /// `ORCHID_IRC_HOST` (default `irc.chat.twitch.tv`), `ORCHID_IRC_TLS` (default `true`),
+    /// `ORCHID_IRC_PORT` (default 6697 with TLS, 6667 without),
+    /// `ORCHID_IRC_LOGIN` and `ORCHID_IRC_TOKEN` (anonymous if unset)
+    pub irc: IrcEndpoint,
+    /// `ORCHID_MOCK_RATE`: mock messages per second, per channel, above 0. Defaults to 2.
+    pub mock_rate: f64,
+    /// `ORCHID_MOCK_CORPUS`: JSON file of `{ "message", "user" }` lines for the mock source.
+    /// Defaults to a small built-in corpus.
+    pub mock_corpus: Option<PathBuf>,
+    /// `ORCHID_RECORD_PATH`: if set, raw IRC messages are recorded to this file
+    pub record_path: Option<PathBuf>,
+    /// `ORCHID_REPLAY_PATH`: recording to play back with the `replay` source
+    pub replay_path: Option<PathBuf>,
+    /// `ORCHID_REPLAY_SPEED`: `original` (default), `max`, or a factor such as `2.5`
+    pub replay_speed: ReplaySpeed,
+    /// `ORCHID_BACKFILL_SIZE`: recent messages per channel sent to new subscribers. Defaults to 50, 0 disables backfill.
+    pub backfill_size: usize,
+    /// `ORCHID_HISTORY`: whether to store chat history in the database. Defaults to `true`.
+    pub history: bool,
+    /// `ORCHID_HISTORY_MAX_AGE_DAYS` and `ORCHID_HISTORY_MAX_MESSAGES`: how much history to keep.
+    /// History is kept forever if neither is set. `ORCHID_HISTORY_MAX_MESSAGES` must be at least 1.
+    pub history_retention: RetentionPolicy,
+    /// `ORCHID_SEARCH_INDEX_PATH`: where to keep the full-text search index of chat history.
+    /// Defaults to `chat_search.sqlite`. Set `ORCHID_SEARCH=false` to disable search.
+    pub search_index_path: Option<PathBuf>,
+    /// `ORCHID_PROCESSORS`: comma-separated message processors, in the order they run.
+    /// Defaults to `filters,colors,chatters,highlights,emotes,mentions`.
+    pub processors: Vec<ProcessorKind>,
+    /// Nickname color correction. `ORCHID_COLOR_BACKGROUND`: the overlay background (default `#18181B`),
+    /// `ORCHID_COLOR_MIN_CONTRAST`: minimum contrast ratio against it (default 4.5),
+    /// `ORCHID_COLOR_PALETTE`: comma-separated `#RRGGBB` colors for users without one
+    pub colors: ColorSettings,
+    /// `ORCHID_SEVENTV_API_URL`: 7TV API base URL, e.g. for a local fixture server. Defaults to `https://7tv.io/v3`.
+    pub seventv_api_url: String,
+    /// `ORCHID_BTTV_API_URL`: BetterTTV API base URL. Defaults to `https://api.betterttv.net/3`.
+    pub bttv_api_url: String,
+    /// Where the image proxy fetches emote images from. `ORCHID_SEVENTV_CDN_URL`: 7TV
+    /// (default `https://cdn.7tv.app/emote`), `ORCHID_FFZ_CDN_URL`: FFZ (default `https://cdn.frankerfacez.com/emote`)
+    pub emote_cdns: EmoteCdns,
+    /// `ORCHID_EMOTE_PRECEDENCE`: comma-separated `provider:tier` emote sets, highest precedence first.
+    /// Providers are `7tv`, `bttv` and `ffz`, tiers `user`, `channel` and `global`; unlisted sets aren't used.
+    /// Defaults to user, channel and global sets, each preferring 7TV, BTTV, then FFZ.
+    pub emote_precedence: Vec<EmoteSource>,
+    /// How long to wait before fetching emotes again. `ORCHID_EMOTE_REFRESH_SECS`: for emotes that were found
+    /// (default 1800), `ORCHID_EMOTE_MISSING_TTL_SECS`: for channels and users a provider doesn't know (default 3600),
+    /// `ORCHID_EMOTE_FAILED_TTL_SECS`: after a failed fetch (default 60)
+    pub emote_ttls: EmoteTtls,
+    /// Per-provider refresh intervals, overriding `ORCHID_EMOTE_REFRESH_SECS`:
+    /// `ORCHID_SEVENTV_REFRESH_SECS`, `ORCHID_BTTV_REFRESH_SECS` and `ORCHID_FFZ_REFRESH_SECS`
+    pub emote_refresh: HashMap<EmoteProvider, Duration>,
+    /// `ORCHID_EMOTE_CACHE`: whether to keep fetched emotes in the database, so they're available straight after a restart.
+    /// Defaults to `true`.
+    pub emote_cache: bool,
+    /// `ORCHID_EMOTE_OFFLINE`: only serve emotes from the database cache, never fetching them. Defaults to `false`.
+    pub emote_offline: bool,
+    /// `ORCHID_EMOTE_PROXY`: serve emote images from `/emotes/:source/:id/:scale`, keeping them on disk,
+    /// rather than linking overlays to the providers' CDNs. Defaults to `true`.
+    pub emote_proxy: bool,
+    /// `ORCHID_EMOTE_IMAGE_DIR`: where proxied emote images are kept. Defaults to `emote_images`.
+    pub emote_image_dir: PathBuf,
+    /// `ORCHID_EMOTE_IMAGE_MAX_BYTES`: largest emote image the proxy will fetch. Defaults to 5 MiB.
+    pub emote_image_max_bytes: usize,
+}
+
+impl Config {
+    pub fn from_env() -> OrchidResult<Self> {
+        let tls = env_or("ORCHID_IRC_TLS", true)?;
+        let irc = IrcEndpoint {
+            host: env_or("ORCHID_IRC_HOST", TWITCH_IRC_HOST.to_string())?,
+            port: env_or(
+                "ORCHID_IRC_PORT",
+                if tls {
+                    TWITCH_IRC_TLS_PORT
+                } else {
+                    TWITCH_IRC_PORT
+                },
+            )?,
+            tls,
+            login: env::var("ORCHID_IRC_LOGIN").ok(),
+            token: env::var("ORCHID_IRC_TOKEN").ok(),
+        };
+
+        let defaults = ColorSettings::default();
+        let emote_ttls = EmoteTtls::default();
+
+        Ok(Self {
+            chat_source: env_or("ORCHID_CHAT_SOURCE", ChatSourceKind::Twitch)?,
+            irc,
+            mock_rate: ensure(
+                "ORCHID_MOCK_RATE",
+                env_or("ORCHID_MOCK_RATE", 2.0)?,
+                |rate| rate.is_finite() && *rate > 0.0,
+            )?,
+            mock_corpus: env::var_os("ORCHID_MOCK_CORPUS").map(PathBuf::from),
+            record_path: env::var_os("ORCHID_RECORD_PATH").map(PathBuf::from),
+            replay_path: env::var_os("ORCHID_REPLAY_PATH").map(PathBuf::from),
+            replay_speed: env_or("ORCHID_REPLAY_SPEED", ReplaySpeed::Original)?,
+            backfill_size: env_or("ORCHID_BACKFILL_SIZE", 50)?,
+            history: env_or("ORCHID_HISTORY", true)?,
+            history_retention: RetentionPolicy {
+                max_age: env_opt::<u64>("ORCHID_HISTORY_MAX_AGE_DAYS")?
+                    .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+                max_rows: env_opt("ORCHID_HISTORY_MAX_MESSAGES")?
+                    .map(|rows| ensure("ORCHID_HISTORY_MAX_MESSAGES", rows, |rows| *rows >= 1))
+                    .transpose()?,
+            },
+            search_index_path: env_or("ORCHID_SEARCH", true)?.then(|| {
+                env::var_os("ORCHID_SEARCH_INDEX_PATH")
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| PathBuf::from("chat_search.sqlite"))
+            }),
+            processors: env_list(
+                "ORCHID_PROCESSORS",
+                vec![
+                    ProcessorKind::Filters,
+                    ProcessorKind::Colors,
+                    ProcessorKind::Chatters,
+                    ProcessorKind::Highlights,
+                    ProcessorKind::Emotes,
+                    ProcessorKind::Mentions,
+                ],
+            )?,
+            colors: ColorSettings {
+                background: env_or("ORCHID_COLOR_BACKGROUND", HexColor(defaults.background))?.0,
+                min_contrast: env_or("ORCHID_COLOR_MIN_CONTRAST", defaults.min_contrast)?,
+                palette: env_list::<HexColor>("ORCHID_COLOR_PALETTE", vec![])?
+                    .into_iter()
+                    .map(|color| color.0)
+                    .collect(),
+            },
+            seventv_api_url: env_or("ORCHID_SEVENTV_API_URL", SEVENTV_API_URL.to_string())?,
+            bttv_api_url: env_or("ORCHID_BTTV_API_URL", BTTV_API_URL.to_string())?,
+            emote_cdns: EmoteCdns {
+                seventv: cdn_url("ORCHID_SEVENTV_CDN_URL", SEVENTV_CDN_URL)?,
+                ffz: cdn_url("ORCHID_FFZ_CDN_URL", FFZ_CDN_URL)?,
+            },
+            emote_precedence: env_list("ORCHID_EMOTE_PRECEDENCE", default_precedence())?,
+            emote_ttls: EmoteTtls {
+                refresh: Duration::from_secs(env_or(
+                    "ORCHID_EMOTE_REFRESH_SECS",
+                    emote_ttls.refresh.as_secs(),
+                )?),
+                missing: Duration::from_secs(env_or(
+                    "ORCHID_EMOTE_MISSING_TTL_SECS",
+                    emote_ttls.missing.as_secs(),
+                )?),
+                failed: Duration::from_secs(env_or(
+                    "ORCHID_EMOTE_FAILED_TTL_SECS",
+                    emote_ttls.failed.as_secs(),
+                )?),
+            },
+            emote_refresh: [
+                (EmoteProvider::SevenTv, "ORCHID_SEVENTV_REFRESH_SECS"),
+                (EmoteProvider::Bttv, "ORCHID_BTTV_REFRESH_SECS"),
+                (EmoteProvider::Ffz, "ORCHID_FFZ_REFRESH_SECS"),
+            ]
+            .into_iter()
+            .filter_map(|(provider, key)| {
+                env_opt(key)
+                    .map(|secs| secs.map(|secs| (provider, Duration::from_secs(secs))))
+                    .transpose()
+            })
+            .collect::<OrchidResult<_>>()?,
+            emote_cache: env_or("ORCHID_EMOTE_CACHE", true)?,
+            emote_offline: env_or("ORCHID_EMOTE_OFFLINE", false)?,
+            emote_proxy: env_or("ORCHID_EMOTE_PROXY", true)?,
+            emote_image_dir: env::var_os("ORCHID_EMOTE_IMAGE_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from("emote_images")),
+            emote_image_max_bytes: env_or("ORCHID_EMOTE_IMAGE_MAX_BYTES", 5 * 1024 * 1024)?,
+        })
+    }
+
+    /// Emote TTLs for a provider, with its own refresh interval if one is set
+    pub fn emote_ttls_for(&self, provider: EmoteProvider) -> EmoteTtls {
+        EmoteTtls {
+            refresh: self
+                .emote_refresh
+                .get(&provider)
+                .copied()
+                .unwrap_or(self.emote_ttls.refresh),
+            ..self.emote_ttls
+        }
+    }
+}
+
+/// Parses an environment variable, falling back to a default if it isn't set.
+fn env_or<T: FromStr>(key: &str, default: T) -> OrchidResult<T> {
+    match env::var(key) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| OrchidError::ConfigError(format!("Invalid value for {}: {}", key, value))),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Rejects a value out of the range an environment variable allows.
+fn ensure<T: Display>(key: &str, value: T, valid: impl FnOnce(&T) -> bool) -> OrchidResult<T> {
+    if valid(&value) {
+        Ok(value)
+    } else {
+        Err(OrchidError::ConfigError(format!(
+            "Invalid value for {}: {}",
+            key, value
+        )))
+    }
+}
+
+/// Parses a comma-separated environment variable, falling back to a default if it isn't set.
+/// A CDN base URL, without a trailing slash
+fn cdn_url(key: &str, default: &str) -> OrchidResult<String> {
+    Ok(env_or(key, default.to_string())?
+        .trim_end_matches('/')
+        .to_string())
+}
+
+fn env_list<T: FromStr<Err = OrchidError>>(key: &str, default: Vec<T>) -> OrchidResult<Vec<T>> {
+    match env::var(key) {
+        Ok(value) => value
+            .split(',')
+            .filter(|item| !item.trim().is_empty())
+            .map(str::parse)
+            .collect(),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Parses an optional environment variable.
+fn env_opt<T: FromStr>(key: &str) -> OrchidResult<Option<T>> {
+    match env::var(key) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| OrchidError::ConfigError(format!("Invalid value for {}: {}", key, value))),
+        Err(_) => Ok(None),
+    }
+}
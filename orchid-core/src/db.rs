@@ -1,7 +1,42 @@
 // As TurboSQL is not your average SQL library (no exposed connections/pools/etc) we'll just have the schema here.
 
 use serde::{Deserialize, Serialize};
-use turbosql::Turbosql;
+use turbosql::{execute, Turbosql};
+
+use crate::twitch::chat::{
+    filter::ChatFilter,
+    highlight::{HighlightKind, HighlightStyle},
+    message::TwitchChatMessage,
+};
+use crate::twitch::emote::store::CachedEmoteSet;
+
+/// Runs database writes in one transaction, rolling them back if any fails.
+/// Turbosql keeps a connection per thread, so everything inside has to run on the calling thread.
+pub fn transaction<T>(
+    writes: impl FnOnce() -> Result<T, turbosql::Error>,
+) -> Result<T, turbosql::Error> {
+    execute!("BEGIN IMMEDIATE")?;
+    match writes() {
+        Ok(value) => {
+            execute!("COMMIT")?;
+            Ok(value)
+        }
+        Err(e) => {
+            let _ = execute!("ROLLBACK");
+            Err(e)
+        }
+    }
+}
+
+/// Replaces the rows a DELETE removes with a new row. For tables with JSON columns,
+/// which can't be bound in an UPDATE. Run it inside a `transaction`, so the old rows aren't lost if the insert fails.
+pub fn replace_row(
+    delete: impl FnOnce() -> Result<usize, turbosql::Error>,
+    row: &impl Turbosql,
+) -> Result<i64, turbosql::Error> {
+    delete()?;
+    row.insert()
+}
 
 #[derive(Serialize, Deserialize)]
 /// Bottom stream layout items. References an ID to another table.
@@ -75,3 +110,149 @@ pub struct BottomLayout {
     /// Layout items, in order from left to right.
     layout_items: Vec<BottomLayoutItems>,
 }
+
+#[derive(Serialize, Deserialize, Turbosql, Default, Clone, Debug)]
+/// A merged chat feed, grouping several channels under a single subscription.
+pub struct ChatFeed {
+    rowid: Option<i64>,
+    /// Unique feed name, used to subscribe to the feed
+    pub name: String,
+    /// Channels in this feed, in display order
+    pub channels: Vec<ChatFeedChannel>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+/// A channel within a ChatFeed
+pub struct ChatFeedChannel {
+    /// Channel name (login)
+    pub channel: String,
+    /// Label shown next to messages from this channel. Defaults to the channel name.
+    pub label: Option<String>,
+    /// Color used for this channel's label. Defaults to a color derived from the channel name.
+    pub color: Option<(u8, u8, u8)>,
+}
+
+#[derive(Serialize, Deserialize, Turbosql, Default, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+/// A stored chat message, as it was sent to clients.
+pub struct ChatLogMessage {
+    pub rowid: Option<i64>,
+    /// Channel name (login)
+    pub channel: String,
+    pub channel_id: String,
+    pub user_id: String,
+    /// Sender login
+    pub user_name: String,
+    pub message_id: String,
+    /// When the message was sent, in unix milliseconds
+    pub timestamp: i64,
+    /// The full message
+    pub message: TwitchChatMessage,
+    /// Whether a moderator removed the message (deletion, ban, timeout or chat clear)
+    pub deleted: bool,
+}
+
+#[derive(Serialize, Deserialize, Turbosql, Default, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+/// A stored moderation event (CLEARCHAT/CLEARMSG).
+pub struct ChatLogInstruction {
+    pub rowid: Option<i64>,
+    /// Channel name (login)
+    pub channel: String,
+    pub msg_type: String,
+    pub msg_subtype: String,
+    /// Message or user ID the instruction applies to
+    pub associated_id: String,
+    /// When the instruction was received, in unix milliseconds
+    pub timestamp: i64,
+    /// `clear_chat`, `ban`, `timeout` or `delete`
+    pub action: String,
+    /// Login of the user whose messages were removed
+    pub target_login: Option<String>,
+    /// ID of the user whose messages were removed
+    pub target_user_id: Option<String>,
+    /// Timeout length in seconds
+    pub timeout_duration: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Turbosql, Default, Clone, Debug)]
+/// The chat filters configured for a channel
+pub struct ChatFilterConfig {
+    pub rowid: Option<i64>,
+    /// Channel name (login)
+    pub channel: String,
+    /// Filters, in the order they're applied
+    pub filters: Vec<ChatFilter>,
+}
+
+#[derive(Serialize, Deserialize, Turbosql, Default, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+/// A rule highlighting matching chat messages
+pub struct HighlightRule {
+    #[serde(rename = "id")]
+    pub rowid: Option<i64>,
+    /// Channel name (login) the rule applies to. Applies to all channels if unset.
+    pub channel: Option<String>,
+    pub kind: HighlightKind,
+    pub style: HighlightStyle,
+    /// Rules are enabled unless they say otherwise
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Turbosql, Default, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+/// Someone who has chatted in a channel
+pub struct Chatter {
+    pub rowid: Option<i64>,
+    /// Channel name (login)
+    pub channel: String,
+    pub user_id: String,
+    /// The user's login, as of their last message
+    pub login: String,
+    /// When we first saw them chat, in unix milliseconds
+    pub first_seen: i64,
+    /// When they last chatted, in unix milliseconds
+    pub last_seen: i64,
+    /// Messages seen from them in this channel
+    pub message_count: i64,
+}
+
+#[derive(Serialize, Deserialize, Turbosql, Default, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+/// What a user looked like when we last saw them chat, in any channel
+pub struct ChatterIdentity {
+    pub rowid: Option<i64>,
+    pub user_id: String,
+    pub login: String,
+    pub display_name: String,
+    /// Name color from their last message
+    pub color: (u8, u8, u8),
+    /// Global badges from their last message (name, version).
+    /// Channel badges, such as subscriber, differ between channels, so they aren't kept.
+    pub badges: Vec<(String, String)>,
+}
+
+#[derive(Serialize, Deserialize, Turbosql, Default, Clone, Debug)]
+/// An emote provider's response for a scope, and the emote sets taken from it
+pub struct EmoteCacheEntry {
+    pub rowid: Option<i64>,
+    /// `ffz`, `bttv` or `7tv`
+    pub provider: String,
+    /// `global`, `channel` or `user`
+    pub tier: String,
+    /// The channel or user the response is for, as the provider's cache keys it
+    pub scope_key: String,
+    /// The raw response body
+    pub response: String,
+    pub sets: Vec<CachedEmoteSet>,
+    /// Whether the provider doesn't have the channel or user
+    pub missing: bool,
+    /// When it was fetched, in unix milliseconds
+    pub fetched_at: i64,
+}
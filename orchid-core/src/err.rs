@@ -9,6 +9,9 @@ pub enum OrchidError {
     #[error("User not found: {0}")]
     UserNotFound(String),
 
+    #[error("Not found: {0}")]
+    NotFound(String),
+
     #[error("I/O error: {0}")]
     IoError(#[from] std::io::Error),
 
@@ -21,9 +24,27 @@ pub enum OrchidError {
     #[error("Authentication failed: {0}")]
     AuthError(String),
 
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] turbosql::Error),
+
+    #[error("Search index error: {0}")]
+    SearchIndexError(#[from] rusqlite::Error),
+
+    #[error("Invalid query: {0}")]
+    InvalidQuery(String),
+
+    #[error("Invalid rule: {0}")]
+    InvalidRule(String),
+
+    #[error("Upstream request failed: {0}")]
+    UpstreamError(String),
+
     #[error("Configuration error: {0}")]
     ConfigError(String),
 
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
     #[error("Unknown error: {0}")]
     Unknown(String),
 }
@@ -42,6 +63,11 @@ impl From<OrchidError> for (StatusCode, Json<serde_json::Value>) {
             ),
             OrchidError::ChannelError(_) => (StatusCode::BAD_REQUEST, "Invalid channel operation"),
             OrchidError::AuthError(_) => (StatusCode::UNAUTHORIZED, "Authentication failed"),
+            OrchidError::NotFound(_) => (StatusCode::NOT_FOUND, "Not found"),
+            OrchidError::InvalidQuery(_) => (StatusCode::BAD_REQUEST, "Invalid query"),
+            OrchidError::InvalidRule(_) => (StatusCode::BAD_REQUEST, "Invalid rule"),
+            OrchidError::UpstreamError(_) => (StatusCode::BAD_GATEWAY, "Upstream request failed"),
+            OrchidError::Conflict(_) => (StatusCode::CONFLICT, "Conflict"),
             _ => (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error"),
         };
 
@@ -1,27 +1,46 @@
 use axum::{
-    extract::{ConnectInfo, Query, State, WebSocketUpgrade},
-    http::StatusCode,
+    extract::{ConnectInfo, Path, Query, State, WebSocketUpgrade},
+    http::{header, StatusCode},
     response::IntoResponse,
-    routing::{any, get},
+    routing::{any, delete, get, post, put},
     Json, Router,
 };
 use axum_extra::TypedHeader;
-use serde::Deserialize;
-use std::{net::SocketAddr, path::PathBuf, sync::Arc};
-use tokio::sync::Mutex;
+use db::{ChatFeed, HighlightRule};
+use err::{OrchidError, OrchidResult};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc};
+use tokio::sync::{mpsc, Mutex, RwLock};
 use tower_http::{
     services::ServeDir,
     trace::{DefaultMakeSpan, TraceLayer},
 };
-use tracing::debug;
+use tracing::{debug, error};
 use twitch::{
-    chat::setup_twitch_chat,
-    emote::{ffz::FrankerFaceZEmoteManager, EmoteHandler},
+    chat::{
+        backfill::RecentMessages,
+        chatters::{ChatterTracker, MentionProcessor},
+        color::ColorProcessor,
+        fanout_to_channel,
+        filter::{ChatFilter, ChatFilters},
+        highlight::{HighlightProcessor, HighlightRules},
+        history::{self, ChatHistory, HistoryQuery},
+        message::EmoteSetChangedEvent,
+        processor::{EmoteProcessor, FilterProcessor, ProcessorChain, ProcessorKind},
+        search::{ChatSearchIndex, SearchQuery},
+        setup_twitch_chat, source, ChatPipeline,
+    },
+    emote::{
+        bttv::BttvEmoteManager, ffz::FrankerFaceZEmoteManager, images::EmoteImageCache,
+        index::EmoteProvider, seventv::SevenTvEmoteManager, store::EmoteStore, EmoteHandler,
+        EmoteScope,
+    },
 };
 use ws::{WebsocketCollection, WebsocketHandler, WsMessage};
 
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+pub mod config;
 pub mod db;
 pub mod err;
 pub mod twitch;
@@ -31,6 +50,12 @@ pub mod ws;
 pub struct AppState {
     ws_collection: Arc<Mutex<WebsocketCollection>>,
     sub_manager: Arc<Mutex<twitch::chat::manager::SubscriptionManager>>,
+    search: Option<ChatSearchIndex>,
+    filters: Arc<RwLock<ChatFilters>>,
+    highlights: Arc<RwLock<HighlightRules>>,
+    chatters: Arc<ChatterTracker>,
+    emotes: Arc<EmoteHandler>,
+    emote_images: Arc<EmoteImageCache>,
 }
 
 #[tokio::main]
@@ -46,26 +71,144 @@ async fn main() {
 
     let assets_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("assets");
 
+    let config = config::Config::from_env().expect("Invalid configuration");
+    let chat_source = source::from_config(&config).expect("Failed to set up chat source");
+
     // setup sub manager
     let sub_manager = twitch::chat::manager::SubscriptionManager::new();
+    if let Err(e) = sub_manager.lock().await.load_feeds() {
+        error!("Failed to load chat feeds: {}", e);
+    }
 
-    let ws_collection = Arc::new(Mutex::new(WebsocketCollection::new(sub_manager.clone())));
+    let filters = ChatFilters::new();
+    if let Err(e) = filters.write().await.load() {
+        error!("Failed to load chat filters: {}", e);
+    }
+
+    let highlights = HighlightRules::new();
+    if let Err(e) = highlights.write().await.load() {
+        error!("Failed to load highlight rules: {}", e);
+    }
+
+    let chatters = Arc::new(ChatterTracker::start());
+    if let Err(e) = chatters.load().await {
+        error!("Failed to load chatters: {}", e);
+    }
+
+    let recent_messages = RecentMessages::new(config.backfill_size);
+    let search = match (&config.search_index_path, config.history) {
+        (Some(path), true) => match ChatSearchIndex::open(path) {
+            Ok(search) => Some(search),
+            Err(e) => {
+                error!("Failed to open chat search index: {}", e);
+                None
+            }
+        },
+        _ => None,
+    };
+    let history = config
+        .history
+        .then(|| ChatHistory::start(config.history_retention, search.clone()));
+
+    let ws_collection = Arc::new(Mutex::new(WebsocketCollection::new(
+        sub_manager.clone(),
+        recent_messages.clone(),
+    )));
 
     // set up emote manager
     let mut em = EmoteHandler::new();
-    em.add_manager(Box::new(FrankerFaceZEmoteManager::new()));
-    let emote_manager = Arc::new(Mutex::new(em));
+    em.set_precedence(config.emote_precedence.clone());
+    let mut ffz = FrankerFaceZEmoteManager::new(config.emote_ttls_for(EmoteProvider::Ffz));
+    let mut bttv = BttvEmoteManager::new(
+        &config.bttv_api_url,
+        config.emote_ttls_for(EmoteProvider::Bttv),
+    );
+    let mut seventv = SevenTvEmoteManager::new(
+        &config.seventv_api_url,
+        config.emote_ttls_for(EmoteProvider::SevenTv),
+    );
+    // load emotes fetched before, so they're there before anything is fetched
+    if config.emote_cache || config.emote_offline {
+        let store = EmoteStore::start();
+        ffz = ffz.with_store(store.clone());
+        bttv = bttv.with_store(store.clone());
+        seventv = seventv.with_store(store);
+    }
+    em.add_manager(Arc::new(ffz));
+    em.add_manager(Arc::new(bttv));
+    em.add_manager(Arc::new(seventv));
+    em.set_offline(config.emote_offline);
+    em.set_image_proxy(config.emote_proxy);
+    // tell overlays when a channel's emotes change
+    let (emote_changes, mut emote_changes_rx) = mpsc::unbounded_channel();
+    em.set_change_notifier(emote_changes);
+    let emote_manager = Arc::new(em);
+    {
+        let ws_collection = ws_collection.clone();
+        let sub_manager = sub_manager.clone();
+        tokio::spawn(async move {
+            while let Some(change) = emote_changes_rx.recv().await {
+                debug!("{} emotes changed in {}", change.provider, change.channel);
+                let json = serde_json::to_string(&EmoteSetChangedEvent::new(&change)).unwrap();
+                fanout_to_channel(
+                    ws_collection.clone(),
+                    sub_manager.clone(),
+                    &change.channel,
+                    json,
+                )
+                .await;
+            }
+        });
+    }
+    sub_manager
+        .lock()
+        .await
+        .set_emote_handler(emote_manager.clone());
+
+    // set up message processing, in the configured order
+    let mut processors = ProcessorChain::new();
+    for kind in &config.processors {
+        match kind {
+            ProcessorKind::Filters => processors.add(FilterProcessor::new(filters.clone())),
+            ProcessorKind::Emotes => processors.add(EmoteProcessor::new(emote_manager.clone())),
+            ProcessorKind::Chatters => processors.add(chatters.clone()),
+            ProcessorKind::Colors => processors.add(ColorProcessor::new(config.colors.clone())),
+            ProcessorKind::Mentions => processors.add(MentionProcessor::new(
+                chatters.clone(),
+                config.colors.clone(),
+            )),
+            ProcessorKind::Highlights => {
+                processors.add(HighlightProcessor::new(highlights.clone()))
+            }
+        }
+    }
 
     // Setup twitch chat
-    let cloned_ws_collection = ws_collection.clone();
-    let cloned_sub_manager = sub_manager.clone();
+    let pipeline = ChatPipeline {
+        state: ws_collection.clone(),
+        sub_manager: sub_manager.clone(),
+        processors: Arc::new(processors),
+        recent_messages,
+        history,
+    };
     let twitch_chat_task = tokio::spawn(async move {
-        setup_twitch_chat(ws_collection, cloned_sub_manager, emote_manager).await;
+        setup_twitch_chat(chat_source, pipeline).await;
     });
 
     let state = AppState {
-        ws_collection: cloned_ws_collection,
+        ws_collection,
         sub_manager,
+        search,
+        filters,
+        highlights,
+        chatters,
+        emotes: emote_manager,
+        emote_images: Arc::new(EmoteImageCache::new(
+            config.emote_image_dir.clone(),
+            config.emote_image_max_bytes,
+            config.emote_offline,
+            config.emote_cdns.clone(),
+        )),
     };
 
     println!("Ok!");
@@ -78,6 +221,28 @@ async fn main() {
         .route("/global_sub", get(global_sub))
         .route("/global_unsub", get(global_unsub))
         .route("/global_subs", get(get_global_subs))
+        .route("/feeds", get(get_feeds).post(set_feed))
+        .route("/feeds/:name", delete(delete_feed))
+        .route("/feed_sub", get(feed_sub))
+        .route("/feed_unsub", get(feed_unsub))
+        .route("/filters/:channel", get(get_filters).post(set_filters))
+        .route("/highlights", get(get_highlights).post(add_highlight))
+        .route(
+            "/highlights/:id",
+            put(update_highlight).delete(delete_highlight),
+        )
+        .route("/chatters/:login", get(get_chatter_profile))
+        .route("/emotes", get(get_emote_catalog))
+        .route("/emotes/search", get(search_emotes))
+        .route("/emotes/reload", post(reload_emotes))
+        .route("/emotes/:source/:id/:scale", get(get_emote_image))
+        .route(
+            "/emotes/:source/:id/:scale/animated",
+            get(get_animated_emote_image),
+        )
+        .route("/history", get(get_history))
+        .route("/history/moderation", get(get_moderation_history))
+        .route("/history/search", get(search_history))
         // host static files in assets folder!
         .nest_service("/", ServeDir::new("../orchid-web/dist"))
         // logging so we can see whats going on
@@ -144,10 +309,26 @@ async fn global_sub(
     Query(query): Query<GlobalSubscriptionQuery>,
     State(state): State<AppState>,
 ) -> impl IntoResponse {
-    let mut mgr = state.sub_manager.lock().await;
-    mgr.subscribe(query.username, "global".to_string())
+    let channel = query.username.to_lowercase();
+    state
+        .sub_manager
+        .lock()
+        .await
+        .subscribe(channel.clone(), "global".to_string())
         .await
         .unwrap();
+
+    // Catch connected clients up. The sub manager lock has to be released first,
+    // the websocket collection is always locked before it.
+    let backfills = state
+        .ws_collection
+        .lock()
+        .await
+        .backfill_channel_globally(&channel)
+        .await;
+    for backfill in backfills {
+        backfill.send().await;
+    }
     StatusCode::OK
 }
 
@@ -166,3 +347,284 @@ async fn get_global_subs(State(state): State<AppState>) -> impl IntoResponse {
     let subs: Vec<String> = subs.iter().map(|s| s.to_string()).collect();
     Json(subs)
 }
+
+async fn get_feeds(State(state): State<AppState>) -> impl IntoResponse {
+    let mgr = state.sub_manager.lock().await;
+    Json(mgr.get_feeds())
+}
+
+async fn set_feed(
+    State(state): State<AppState>,
+    Json(feed): Json<ChatFeed>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let mut mgr = state.sub_manager.lock().await;
+    mgr.set_feed(feed).await?;
+    Ok(StatusCode::OK)
+}
+
+async fn delete_feed(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let mut mgr = state.sub_manager.lock().await;
+    mgr.remove_feed(&name).await?;
+    Ok(StatusCode::OK)
+}
+
+#[derive(Deserialize)]
+struct FeedSubscriptionQuery {
+    feed: String,
+}
+
+async fn feed_sub(
+    Query(query): Query<FeedSubscriptionQuery>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    state
+        .sub_manager
+        .lock()
+        .await
+        .subscribe_feed(&query.feed, "global".to_string())
+        .await?;
+
+    let backfills = state
+        .ws_collection
+        .lock()
+        .await
+        .backfill_feed_globally(&query.feed)
+        .await;
+    for backfill in backfills {
+        backfill.send().await;
+    }
+    Ok(StatusCode::OK)
+}
+
+async fn feed_unsub(
+    Query(query): Query<FeedSubscriptionQuery>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let mut mgr = state.sub_manager.lock().await;
+    mgr.unsubscribe_feed(&query.feed, "global").await;
+    StatusCode::OK
+}
+
+/// Runs a database query on a blocking thread, so it doesn't hold up the runtime
+async fn blocking<T: Send + 'static>(
+    query: impl FnOnce() -> OrchidResult<T> + Send + 'static,
+) -> OrchidResult<T> {
+    tokio::task::spawn_blocking(query)
+        .await
+        .map_err(|e| OrchidError::Unknown(e.to_string()))?
+}
+
+async fn get_history(
+    Query(query): Query<HistoryQuery>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    Ok(Json(
+        blocking(move || history::query_messages(&query)).await?,
+    ))
+}
+
+async fn get_moderation_history(
+    Query(query): Query<HistoryQuery>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    Ok(Json(
+        blocking(move || history::query_instructions(&query)).await?,
+    ))
+}
+
+async fn search_history(
+    Query(query): Query<SearchQuery>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let Some(search) = state.search else {
+        return Err(OrchidError::NotFound("Chat search is disabled".to_string()).into());
+    };
+    Ok(Json(blocking(move || search.search(&query)).await?))
+}
+
+#[derive(Serialize)]
+struct ChannelFilters {
+    filters: Vec<ChatFilter>,
+    /// Filter name -> messages matched since startup
+    hits: HashMap<String, u64>,
+}
+
+async fn get_filters(
+    Path(channel): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let channel = channel.to_lowercase();
+    let filters = state.filters.read().await;
+    Json(ChannelFilters {
+        filters: filters.get_filters(&channel),
+        hits: filters.get_hits(&channel),
+    })
+}
+
+async fn set_filters(
+    Path(channel): Path<String>,
+    State(state): State<AppState>,
+    Json(filters): Json<Vec<ChatFilter>>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    state.filters.write().await.set_filters(&channel, filters)?;
+    Ok(StatusCode::OK)
+}
+
+#[derive(Deserialize)]
+struct HighlightQuery {
+    channel: Option<String>,
+}
+
+async fn get_highlights(
+    Query(query): Query<HighlightQuery>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let channel = query.channel.map(|channel| channel.to_lowercase());
+    Json(state.highlights.read().await.get_rules(channel.as_deref()))
+}
+
+async fn add_highlight(
+    State(state): State<AppState>,
+    Json(rule): Json<HighlightRule>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let rule = state.highlights.write().await.add_rule(rule)?;
+    Ok(Json(rule))
+}
+
+async fn update_highlight(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    Json(rule): Json<HighlightRule>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let rule = state.highlights.write().await.update_rule(id, rule)?;
+    Ok(Json(rule))
+}
+
+async fn delete_highlight(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    state.highlights.write().await.remove_rule(id)?;
+    Ok(StatusCode::OK)
+}
+
+async fn get_chatter_profile(
+    Path(login): Path<String>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    Ok(Json(state.chatters.get_profile(&login).await?))
+}
+
+/// Default and most results for emote search
+const DEFAULT_EMOTE_SEARCH_LIMIT: usize = 10;
+const MAX_EMOTE_SEARCH_LIMIT: usize = 100;
+
+#[derive(Deserialize)]
+struct EmoteCatalogQuery {
+    channel: Option<String>,
+    /// Login of the user whose personal emotes to include
+    user: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct EmoteSearchQuery {
+    channel: Option<String>,
+    user: Option<String>,
+    prefix: String,
+    limit: Option<usize>,
+}
+
+/// The emote scope for a channel and user, looking the user's ID up from chatters we've seen
+async fn emote_scope(
+    state: &AppState,
+    channel: Option<String>,
+    user: Option<String>,
+) -> EmoteScope {
+    let mut scope = match channel {
+        Some(channel) => state.emotes.channel_scope(&channel.to_lowercase()),
+        None => EmoteScope::default(),
+    };
+    if let Some(user) = user {
+        let login = user.to_lowercase();
+        scope.user_id = state.chatters.user_id(&login).await.unwrap_or_default();
+        scope.user_name = login;
+    }
+    scope
+}
+
+/// Every emote usable in a channel, optionally including a user's personal emotes
+async fn get_emote_catalog(
+    Query(query): Query<EmoteCatalogQuery>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let scope = emote_scope(&state, query.channel, query.user).await;
+    Json(state.emotes.catalog(&scope).await)
+}
+
+/// Emotes whose names start with a prefix, for autocomplete
+async fn search_emotes(
+    Query(query): Query<EmoteSearchQuery>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_EMOTE_SEARCH_LIMIT)
+        .clamp(1, MAX_EMOTE_SEARCH_LIMIT);
+    let scope = emote_scope(&state, query.channel, query.user).await;
+    Json(state.emotes.search(&scope, &query.prefix, limit).await)
+}
+
+#[derive(Deserialize)]
+struct EmoteReloadQuery {
+    channel: Option<String>,
+}
+
+/// Fetches a channel's emotes again, or the global emotes if no channel is given,
+/// reporting which providers were fetched again and whose emotes changed
+async fn reload_emotes(
+    Query(query): Query<EmoteReloadQuery>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let channel = query.channel.map(|channel| channel.to_lowercase());
+    Ok(Json(state.emotes.reload(channel.as_deref()).await?))
+}
+
+/// Serves an emote image from disk, fetching it from the provider's CDN the first time
+async fn get_emote_image(
+    Path((source, id, scale)): Path<(String, String, u8)>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    emote_image(state, source, id, scale, false).await
+}
+
+async fn get_animated_emote_image(
+    Path((source, id, scale)): Path<(String, String, u8)>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    emote_image(state, source, id, scale, true).await
+}
+
+async fn emote_image(
+    state: AppState,
+    source: String,
+    id: String,
+    scale: u8,
+    animated: bool,
+) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
+    let provider: EmoteProvider = source
+        .parse()
+        .map_err(|_| OrchidError::NotFound(format!("Emote source {}", source)))?;
+    let image = state
+        .emote_images
+        .get(provider, &id, scale, animated)
+        .await?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, image.content_type),
+            // An emote ID's images never change
+            (header::CACHE_CONTROL, "public, max-age=31536000, immutable"),
+        ],
+        image.bytes,
+    ))
+}
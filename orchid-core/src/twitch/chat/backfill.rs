@@ -0,0 +1,60 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+
+use tokio::sync::Mutex;
+
+use super::message::{ModerationAction, TwitchChatMessage, TwitchInstructionMessage};
+
+/// The last few processed messages of each channel, sent to clients when they subscribe
+/// so overlays don't start out empty.
+pub struct RecentMessages {
+    /// Messages kept per channel
+    capacity: usize,
+    channels: HashMap<String, VecDeque<TwitchChatMessage>>,
+}
+
+impl RecentMessages {
+    pub fn new(capacity: usize) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Self {
+            capacity,
+            channels: HashMap::new(),
+        }))
+    }
+
+    pub fn push(&mut self, msg: TwitchChatMessage) {
+        if self.capacity == 0 {
+            return;
+        }
+        let messages = self.channels.entry(msg.channel.clone()).or_default();
+        if messages.len() >= self.capacity {
+            messages.pop_front();
+        }
+        messages.push_back(msg);
+    }
+
+    /// Drops messages removed by moderators, so they're never backfilled
+    pub fn apply_instruction(&mut self, instruction: &TwitchInstructionMessage) {
+        let Some(messages) = self.channels.get_mut(&instruction.channel) else {
+            return;
+        };
+        match instruction.action {
+            ModerationAction::ClearChat => messages.clear(),
+            ModerationAction::Ban | ModerationAction::Timeout => {
+                messages.retain(|msg| msg.user.user_id != instruction.associated_id)
+            }
+            ModerationAction::Delete => {
+                messages.retain(|msg| msg.message_id != instruction.associated_id)
+            }
+        }
+    }
+
+    /// Gets a channel's recent messages, oldest first
+    pub fn recent(&self, channel: &str) -> Vec<TwitchChatMessage> {
+        self.channels
+            .get(channel)
+            .map(|messages| messages.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
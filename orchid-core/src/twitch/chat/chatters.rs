@@ -0,0 +1,451 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use serde::Serialize;
+use tokio::sync::{
+    mpsc::{self, UnboundedSender},
+    Mutex,
+};
+use tracing::{debug, error, info};
+use turbosql::{execute, select, Turbosql};
+
+use super::{
+    color::ColorSettings,
+    message::{MessageMention, TwitchChatMessage},
+    processor::MessageProcessor,
+};
+use crate::{
+    db::{replace_row, transaction, Chatter, ChatterIdentity},
+    err::{OrchidError, OrchidResult},
+};
+
+/// Longest login Twitch allows
+const MAX_LOGIN_LEN: usize = 25;
+/// Badges that belong to a channel rather than the user, so differ from channel to channel
+const CHANNEL_BADGES: &[&str] = &[
+    "broadcaster",
+    "moderator",
+    "lead_moderator",
+    "vip",
+    "subscriber",
+    "founder",
+    "bits",
+    "bits-leader",
+    "sub-gifter",
+    "sub-gift-leader",
+    "artist-badge",
+];
+
+/// Most changes written in one transaction
+const MAX_WRITE_BATCH: usize = 500;
+
+/// A change for the database writer
+enum ChatterWrite {
+    Chatter(Chatter),
+    Identity(ChatterIdentity),
+}
+
+/// Changes waiting to be written. Later changes to the same row replace earlier ones.
+#[derive(Default)]
+struct ChatterBatch {
+    chatters: HashMap<(String, String), Chatter>,
+    identities: HashMap<String, ChatterIdentity>,
+}
+
+impl ChatterBatch {
+    fn add(&mut self, write: ChatterWrite) {
+        match write {
+            ChatterWrite::Chatter(chatter) => {
+                let key = (chatter.channel.clone(), chatter.user_id.clone());
+                self.chatters.insert(key, chatter);
+            }
+            ChatterWrite::Identity(identity) => {
+                self.identities.insert(identity.user_id.clone(), identity);
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.chatters.len() + self.identities.len()
+    }
+
+    /// Writes the batch in one transaction
+    fn write(self) -> Result<(), turbosql::Error> {
+        transaction(|| {
+            for chatter in self.chatters.into_values() {
+                let updated = execute!(
+                    "UPDATE chatter SET login = ?, last_seen = ?, message_count = ? WHERE channel = ? AND user_id = ?",
+                    chatter.login,
+                    chatter.last_seen,
+                    chatter.message_count,
+                    chatter.channel,
+                    chatter.user_id
+                )?;
+                if updated == 0 {
+                    chatter.insert()?;
+                }
+            }
+            for identity in self.identities.into_values() {
+                replace_row(
+                    || {
+                        execute!(
+                            "DELETE FROM chatteridentity WHERE user_id = ?",
+                            identity.user_id
+                        )
+                    },
+                    &identity,
+                )?;
+            }
+            Ok(())
+        })
+    }
+}
+
+#[derive(Default)]
+struct ChatterState {
+    /// (channel, user ID) -> chatter
+    chatters: HashMap<(String, String), Chatter>,
+    /// User ID -> identity
+    identities: HashMap<String, ChatterIdentity>,
+    /// Login -> user ID
+    logins: HashMap<String, String>,
+    /// Channels we know chatters of. Nobody is new to a channel we've only just started tracking.
+    channels: HashSet<String>,
+}
+
+/// A chatter's identity, with their activity in each channel
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatterProfile {
+    #[serde(flatten)]
+    pub identity: ChatterIdentity,
+    pub channels: Vec<Chatter>,
+}
+
+/// Remembers who has chatted in each channel, flagging chatters we haven't seen before,
+/// and what each chatter looked like last time, for coloring mentions.
+/// Chatters are kept in memory and written to the database in batches on a blocking thread.
+pub struct ChatterTracker {
+    state: Mutex<ChatterState>,
+    tx: UnboundedSender<ChatterWrite>,
+}
+
+impl ChatterTracker {
+    /// Starts the database writer. Known chatters are loaded separately, with `load`.
+    pub fn start() -> Self {
+        let (tx, mut rx) = mpsc::unbounded_channel::<ChatterWrite>();
+        tokio::task::spawn_blocking(move || {
+            while let Some(write) = rx.blocking_recv() {
+                // Take whatever else is waiting, so busy chat is written a batch at a time
+                let mut batch = ChatterBatch::default();
+                batch.add(write);
+                while batch.len() < MAX_WRITE_BATCH {
+                    match rx.try_recv() {
+                        Ok(write) => batch.add(write),
+                        Err(_) => break,
+                    }
+                }
+                if let Err(e) = batch.write() {
+                    error!("Failed to store chatters: {}", e);
+                }
+            }
+        });
+
+        Self {
+            state: Mutex::new(ChatterState::default()),
+            tx,
+        }
+    }
+
+    /// Loads known chatters. The first time, chatters are taken from chat history,
+    /// so people who chatted before chatters were tracked aren't flagged as new.
+    pub async fn load(&self) -> OrchidResult<()> {
+        let mut chatters = select!(Vec<Chatter>)?;
+        if chatters.is_empty() {
+            let seeded = execute!(
+                "INSERT INTO chatter (channel, user_id, login, first_seen, last_seen, message_count) SELECT channel, user_id, MAX(user_name), MIN(timestamp), MAX(timestamp), COUNT(*) FROM chatlogmessage GROUP BY channel, user_id"
+            )?;
+            if seeded > 0 {
+                info!("Seeded {} chatters from chat history", seeded);
+                chatters = select!(Vec<Chatter>)?;
+            }
+        }
+
+        let mut state = self.state.lock().await;
+        for chatter in chatters {
+            state.channels.insert(chatter.channel.clone());
+            let key = (chatter.channel.clone(), chatter.user_id.clone());
+            state.chatters.insert(key, chatter);
+        }
+        for identity in select!(Vec<ChatterIdentity>)? {
+            state
+                .logins
+                .insert(identity.login.clone(), identity.user_id.clone());
+            state.identities.insert(identity.user_id.clone(), identity);
+        }
+        debug!(
+            "Loaded {} chatters ({} identities)",
+            state.chatters.len(),
+            state.identities.len()
+        );
+        Ok(())
+    }
+
+    pub async fn get_chatter(&self, channel: &str, user_id: &str) -> Option<Chatter> {
+        self.state
+            .lock()
+            .await
+            .chatters
+            .get(&(channel.to_string(), user_id.to_string()))
+            .cloned()
+    }
+
+    /// Gets a chatter's identity and per-channel activity by login
+    pub async fn get_profile(&self, login: &str) -> OrchidResult<ChatterProfile> {
+        let state = self.state.lock().await;
+        let identity = state
+            .logins
+            .get(&login.to_lowercase())
+            .and_then(|user_id| state.identities.get(user_id))
+            .cloned()
+            .ok_or_else(|| OrchidError::NotFound(format!("Chatter {}", login)))?;
+
+        let mut channels: Vec<Chatter> = state
+            .chatters
+            .values()
+            .filter(|chatter| chatter.user_id == identity.user_id)
+            .cloned()
+            .collect();
+        channels.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+
+        Ok(ChatterProfile { identity, channels })
+    }
+
+    /// The user ID of a login we've seen chat
+    pub async fn user_id(&self, login: &str) -> Option<String> {
+        self.state
+            .lock()
+            .await
+            .logins
+            .get(&login.to_lowercase())
+            .cloned()
+    }
+
+    /// Finds the `@mentions` in a message, resolving them to known chatters
+    pub async fn resolve_mentions(
+        &self,
+        message: &str,
+        colors: &ColorSettings,
+    ) -> Vec<MessageMention> {
+        let mentions = find_mentions(message, colors);
+        if mentions.is_empty() {
+            return mentions;
+        }
+
+        let state = self.state.lock().await;
+        mentions
+            .into_iter()
+            .map(|mut mention| {
+                let identity = state
+                    .logins
+                    .get(&mention.login)
+                    .and_then(|user_id| state.identities.get(user_id));
+                if let Some(identity) = identity {
+                    mention.user_id = Some(identity.user_id.clone());
+                    mention.display_name = Some(identity.display_name.clone());
+                    mention.color = identity.color;
+                }
+                mention
+            })
+            .collect()
+    }
+}
+
+/// Finds `@login` mentions in text. Mentions are given the login's fallback color until resolved.
+fn find_mentions(text: &str, colors: &ColorSettings) -> Vec<MessageMention> {
+    let mut mentions = vec![];
+    let mut chars = text.chars().peekable();
+    // Position in UTF-16 code units
+    let mut pos = 0;
+    let mut prev: Option<char> = None;
+
+    while let Some(c) = chars.next() {
+        let start = pos;
+        pos += c.len_utf16();
+        let at_word_start = !prev.is_some_and(|p| p.is_alphanumeric() || p == '_');
+        prev = Some(c);
+        if c != '@' || !at_word_start {
+            continue;
+        }
+
+        let mut login = String::new();
+        while let Some(&next) = chars.peek() {
+            if !(next.is_ascii_alphanumeric() || next == '_') {
+                break;
+            }
+            login.push(next.to_ascii_lowercase());
+            pos += 1;
+            prev = Some(next);
+            chars.next();
+        }
+        if login.is_empty() || login.len() > MAX_LOGIN_LEN {
+            continue;
+        }
+
+        mentions.push(MessageMention {
+            start,
+            end: pos,
+            color: colors.readable_color(colors.fallback_color(&login)),
+            login,
+            user_id: None,
+            display_name: None,
+        });
+    }
+    mentions
+}
+
+#[async_trait]
+impl MessageProcessor for ChatterTracker {
+    async fn process(&self, msg: &mut TwitchChatMessage) -> bool {
+        let now = msg.timestamp_millis();
+        let key = (msg.channel.clone(), msg.user.user_id.clone());
+
+        let mut state = self.state.lock().await;
+        msg.new_chatter =
+            state.channels.contains(&msg.channel) && !state.chatters.contains_key(&key);
+        if !state.channels.contains(&msg.channel) {
+            state.channels.insert(msg.channel.clone());
+        }
+        let chatter = state.chatters.entry(key).or_insert_with(|| Chatter {
+            rowid: None,
+            channel: msg.channel.clone(),
+            user_id: msg.user.user_id.clone(),
+            login: msg.user.user_name.clone(),
+            first_seen: now,
+            last_seen: now,
+            message_count: 0,
+        });
+        chatter.login = msg.user.user_name.clone();
+        chatter.last_seen = now;
+        chatter.message_count += 1;
+        let _ = self.tx.send(ChatterWrite::Chatter(chatter.clone()));
+
+        let identity = ChatterIdentity {
+            rowid: None,
+            user_id: msg.user.user_id.clone(),
+            login: msg.user.user_name.to_lowercase(),
+            display_name: msg.user.display_name.clone(),
+            color: msg.nickname_color,
+            badges: msg
+                .user_badges
+                .iter()
+                .filter(|(name, _)| !CHANNEL_BADGES.contains(&name.as_str()))
+                .cloned()
+                .collect(),
+        };
+        let changed = match state.identities.get(&identity.user_id) {
+            Some(known) => {
+                known.login != identity.login
+                    || known.display_name != identity.display_name
+                    || known.color != identity.color
+                    || known.badges != identity.badges
+            }
+            None => true,
+        };
+        if changed {
+            if let Some(old) = state.identities.get(&identity.user_id) {
+                if old.login != identity.login {
+                    let old_login = old.login.clone();
+                    state.logins.remove(&old_login);
+                }
+            }
+            state
+                .logins
+                .insert(identity.login.clone(), identity.user_id.clone());
+            state
+                .identities
+                .insert(identity.user_id.clone(), identity.clone());
+            let _ = self.tx.send(ChatterWrite::Identity(identity));
+        }
+        true
+    }
+}
+
+/// Links `@mentions` in messages to the chatters they refer to
+pub struct MentionProcessor {
+    chatters: Arc<ChatterTracker>,
+    /// For coloring mentions of chatters we haven't seen
+    colors: ColorSettings,
+}
+
+impl MentionProcessor {
+    pub fn new(chatters: Arc<ChatterTracker>, colors: ColorSettings) -> Self {
+        Self { chatters, colors }
+    }
+}
+
+#[async_trait]
+impl MessageProcessor for MentionProcessor {
+    async fn process(&self, msg: &mut TwitchChatMessage) -> bool {
+        msg.mentions = self
+            .chatters
+            .resolve_mentions(&msg.message, &self.colors)
+            .await;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spans(text: &str) -> Vec<(usize, usize, String)> {
+        find_mentions(text, &ColorSettings::default())
+            .into_iter()
+            .map(|m| (m.start, m.end, m.login))
+            .collect()
+    }
+
+    #[test]
+    fn finds_mentions_and_lowercases_logins() {
+        assert_eq!(
+            spans("hi @Alice and @bob_2!"),
+            vec![(3, 9, "alice".to_string()), (14, 20, "bob_2".to_string()),]
+        );
+    }
+
+    #[test]
+    fn mentions_need_a_word_start() {
+        assert!(spans("mail me at someone@example.com").is_empty());
+        assert!(spans("foo_@bar").is_empty());
+        assert_eq!(spans("(@bar)"), vec![(1, 5, "bar".to_string())]);
+    }
+
+    #[test]
+    fn skips_empty_and_overlong_logins() {
+        assert!(spans("@ @@ @!").is_empty());
+        assert!(spans(&format!("@{}", "a".repeat(MAX_LOGIN_LEN + 1))).is_empty());
+        assert_eq!(spans(&format!("@{}", "a".repeat(MAX_LOGIN_LEN))).len(), 1);
+    }
+
+    #[test]
+    fn positions_are_utf16() {
+        // The emoji is two UTF-16 code units, the accented letter one
+        assert_eq!(spans("😀é @alice"), vec![(4, 10, "alice".to_string())]);
+    }
+
+    #[test]
+    fn mentions_get_readable_fallback_colors() {
+        let colors = ColorSettings::default();
+        let mention = find_mentions("@alice", &colors).remove(0);
+        assert_eq!(
+            mention.color,
+            colors.readable_color(colors.fallback_color("alice"))
+        );
+        assert_eq!(mention.user_id, None);
+        assert_eq!(mention.display_name, None);
+    }
+}
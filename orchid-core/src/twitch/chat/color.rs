@@ -0,0 +1,274 @@
+use std::str::FromStr;
+
+use async_trait::async_trait;
+
+use super::{message::TwitchChatMessage, processor::MessageProcessor, username_to_color};
+use crate::err::OrchidError;
+
+/// Lightness steps tried when correcting a color
+const CORRECTION_STEPS: usize = 16;
+
+/// A `#RRGGBB` color, for configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HexColor(pub (u8, u8, u8));
+
+impl FromStr for HexColor {
+    type Err = OrchidError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_hex_color(s.trim())
+            .map(HexColor)
+            .ok_or_else(|| OrchidError::ConfigError(format!("Invalid color: {}", s)))
+    }
+}
+
+/// Parses a `#RRGGBB` color
+pub fn parse_hex_color(color: &str) -> Option<(u8, u8, u8)> {
+    let hex = color.strip_prefix('#')?;
+    if hex.len() != 6 {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    Some((channel(0)?, channel(2)?, channel(4)?))
+}
+
+/// How nickname colors are adjusted for the overlay
+#[derive(Debug, Clone)]
+pub struct ColorSettings {
+    /// The overlay background colors are corrected against
+    pub background: (u8, u8, u8),
+    /// Minimum contrast ratio against the background, from 1 (none) to 21 (black on white)
+    pub min_contrast: f64,
+    /// Colors for users who haven't picked one. Colors are derived from the login if empty.
+    pub palette: Vec<(u8, u8, u8)>,
+}
+
+impl Default for ColorSettings {
+    fn default() -> Self {
+        Self {
+            background: (0x18, 0x18, 0x1b),
+            min_contrast: 4.5,
+            palette: vec![],
+        }
+    }
+}
+
+impl ColorSettings {
+    /// Color for a user who hasn't picked one
+    pub fn fallback_color(&self, login: &str) -> (u8, u8, u8) {
+        if self.palette.is_empty() {
+            return username_to_color(login);
+        }
+        let hash: u32 = login
+            .chars()
+            .fold(0, |acc, c| acc.wrapping_add(c as u32).wrapping_mul(31));
+        self.palette[hash as usize % self.palette.len()]
+    }
+
+    /// Lightens or darkens a color until it's readable on the background, keeping its hue.
+    /// Colors that are already readable are returned as is.
+    pub fn readable_color(&self, color: (u8, u8, u8)) -> (u8, u8, u8) {
+        let background = relative_luminance(self.background);
+        if contrast_ratio(relative_luminance(color), background) >= self.min_contrast {
+            return color;
+        }
+
+        let (hue, saturation, lightness) = rgb_to_hsl(color);
+        let contrast = |lightness: f64| {
+            contrast_ratio(
+                relative_luminance(hsl_to_rgb(hue, saturation, lightness)),
+                background,
+            )
+        };
+
+        // Mid-luminance backgrounds may be readable against either extreme, or neither.
+        // Search toward each extreme that's readable, and keep the smallest change.
+        let corrected = [1.0, 0.0]
+            .into_iter()
+            .filter(|&target| contrast(target) >= self.min_contrast)
+            .map(|target| {
+                // Find the smallest lightness change that's readable
+                let (mut near, mut far) = (lightness, target);
+                for _ in 0..CORRECTION_STEPS {
+                    let mid = (near + far) / 2.0;
+                    if contrast(mid) >= self.min_contrast {
+                        far = mid;
+                    } else {
+                        near = mid;
+                    }
+                }
+                far
+            })
+            .min_by(|a, b| (a - lightness).abs().total_cmp(&(b - lightness).abs()));
+
+        // Neither extreme is readable enough, settle for the more readable one
+        let corrected = corrected.unwrap_or_else(|| {
+            if contrast(1.0) >= contrast(0.0) {
+                1.0
+            } else {
+                0.0
+            }
+        });
+        hsl_to_rgb(hue, saturation, corrected)
+    }
+}
+
+/// WCAG relative luminance, from 0 (black) to 1 (white)
+pub fn relative_luminance((r, g, b): (u8, u8, u8)) -> f64 {
+    let linear = |channel: u8| {
+        let c = channel as f64 / 255.0;
+        if c <= 0.03928 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    0.2126 * linear(r) + 0.7152 * linear(g) + 0.0722 * linear(b)
+}
+
+/// WCAG contrast ratio between two luminances
+pub fn contrast_ratio(a: f64, b: f64) -> f64 {
+    let (light, dark) = if a > b { (a, b) } else { (b, a) };
+    (light + 0.05) / (dark + 0.05)
+}
+
+/// Converts RGB to hue (0-360), saturation and lightness (0-1)
+pub fn rgb_to_hsl((r, g, b): (u8, u8, u8)) -> (f64, f64, f64) {
+    let (r, g, b) = (r as f64 / 255.0, g as f64 / 255.0, b as f64 / 255.0);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let lightness = (max + min) / 2.0;
+    let delta = max - min;
+    if delta == 0.0 {
+        return (0.0, 0.0, lightness);
+    }
+
+    let saturation = delta / (1.0 - (2.0 * lightness - 1.0).abs());
+    let hue = if max == r {
+        60.0 * (((g - b) / delta) % 6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    (hue.rem_euclid(360.0), saturation, lightness)
+}
+
+/// Converts hue (0-360), saturation and lightness (0-1) to RGB
+pub fn hsl_to_rgb(hue: f64, saturation: f64, lightness: f64) -> (u8, u8, u8) {
+    let c = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let x = c * (1.0 - ((hue / 60.0) % 2.0 - 1.0).abs());
+    let m = lightness - c / 2.0;
+
+    let (r, g, b) = match (hue / 60.0) as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+
+    (
+        ((r + m) * 255.0) as u8,
+        ((g + m) * 255.0) as u8,
+        ((b + m) * 255.0) as u8,
+    )
+}
+
+/// Gives users without a color one from the configured palette,
+/// and corrects nickname colors for readability on the overlay.
+pub struct ColorProcessor {
+    settings: ColorSettings,
+}
+
+impl ColorProcessor {
+    pub fn new(settings: ColorSettings) -> Self {
+        Self { settings }
+    }
+}
+
+#[async_trait]
+impl MessageProcessor for ColorProcessor {
+    async fn process(&self, msg: &mut TwitchChatMessage) -> bool {
+        let color = msg
+            .original_color
+            .unwrap_or_else(|| self.settings.fallback_color(&msg.user.user_name));
+        msg.nickname_color = self.settings.readable_color(color);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(background: (u8, u8, u8), min_contrast: f64) -> ColorSettings {
+        ColorSettings {
+            background,
+            min_contrast,
+            palette: vec![],
+        }
+    }
+
+    fn contrast(a: (u8, u8, u8), b: (u8, u8, u8)) -> f64 {
+        contrast_ratio(relative_luminance(a), relative_luminance(b))
+    }
+
+    #[test]
+    fn parses_hex_colors() {
+        assert_eq!(parse_hex_color("#FF7F00"), Some((255, 127, 0)));
+        assert_eq!(parse_hex_color("#1e90ff"), Some((30, 144, 255)));
+    }
+
+    #[test]
+    fn rejects_malformed_hex_colors() {
+        for color in ["FF7F00", "#FF7F0", "#FF7F000", "#GG7F00", "#", "", "#ÿÿÿ"] {
+            assert_eq!(parse_hex_color(color), None, "{}", color);
+        }
+    }
+
+    #[test]
+    fn readable_colors_are_kept() {
+        let settings = settings((0x18, 0x18, 0x1b), 4.5);
+        assert_eq!(settings.readable_color((255, 255, 255)), (255, 255, 255));
+        assert_eq!(settings.readable_color((255, 127, 80)), (255, 127, 80));
+    }
+
+    #[test]
+    fn dark_colors_are_lightened_on_dark_backgrounds() {
+        let background = (0x18, 0x18, 0x1b);
+        let color = (0, 0, 255);
+        let corrected = settings(background, 4.5).readable_color(color);
+        assert!(contrast(corrected, background) >= 4.5);
+        assert!(relative_luminance(corrected) > relative_luminance(color));
+        // Still blue
+        assert!(corrected.2 > corrected.0 && corrected.2 > corrected.1);
+    }
+
+    #[test]
+    fn light_colors_are_darkened_on_light_backgrounds() {
+        let background = (255, 255, 255);
+        let color = (255, 255, 0);
+        let corrected = settings(background, 4.5).readable_color(color);
+        assert!(contrast(corrected, background) >= 4.5);
+        assert!(relative_luminance(corrected) < relative_luminance(color));
+    }
+
+    #[test]
+    fn mid_backgrounds_go_whichever_way_is_readable() {
+        // Black is readable on this gray, white isn't quite
+        let background = (0x77, 0x77, 0x77);
+        let color = (0x80, 0x80, 0xff);
+        let corrected = settings(background, 4.5).readable_color(color);
+        assert!(contrast(corrected, background) >= 4.5);
+        assert!(relative_luminance(corrected) < relative_luminance(background));
+    }
+
+    #[test]
+    fn unreachable_contrast_settles_for_the_more_readable_extreme() {
+        let background = (0x77, 0x77, 0x77);
+        let corrected = settings(background, 21.0).readable_color((0x80, 0x80, 0xff));
+        assert_eq!(corrected, (0, 0, 0));
+    }
+}
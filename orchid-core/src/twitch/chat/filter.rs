@@ -0,0 +1,328 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tracing::debug;
+use turbosql::{execute, select, Turbosql};
+
+use super::message::TwitchChatMessage;
+use crate::{db::ChatFilterConfig, err::OrchidResult};
+
+/// Bots hidden by the bot filter when it doesn't list its own
+const DEFAULT_BOTS: &[&str] = &[
+    "nightbot",
+    "streamelements",
+    "streamlabs",
+    "moobot",
+    "fossabot",
+    "wizebot",
+];
+/// Badges that allow posting links past the link filter
+const LINK_BADGES: &[&str] = &["subscriber", "founder", "vip", "moderator", "broadcaster"];
+/// Combining marks allowed on a single character before the rest count as zalgo
+const MAX_COMBINING_MARKS: usize = 2;
+
+/// What a filter checks messages for
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum FilterKind {
+    /// Messages from known bots. Defaults to common bots (Nightbot, StreamElements, ...) if empty.
+    Bots {
+        #[serde(default)]
+        names: Vec<String>,
+    },
+    /// Messages starting with a command prefix, `!` by default
+    Commands {
+        #[serde(default = "default_command_prefix")]
+        prefix: String,
+    },
+    /// Messages containing any of these words (case-insensitive)
+    BannedWords { words: Vec<String> },
+    /// Links from users without a subscriber, VIP or moderator badge
+    Links,
+    /// Stacked combining characters (zalgo text) and control characters
+    Zalgo,
+}
+
+fn default_command_prefix() -> String {
+    "!".to_string()
+}
+
+impl FilterKind {
+    /// Name used for hit counters and message tags
+    pub fn name(&self) -> &'static str {
+        match self {
+            FilterKind::Bots { .. } => "bots",
+            FilterKind::Commands { .. } => "commands",
+            FilterKind::BannedWords { .. } => "bannedWords",
+            FilterKind::Links => "links",
+            FilterKind::Zalgo => "zalgo",
+        }
+    }
+}
+
+/// What happens to a message a filter matches
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum FilterAction {
+    /// Don't send the message at all
+    Drop,
+    /// Replace the offending text (or the whole message) with asterisks. Zalgo is stripped instead.
+    Mask,
+    /// Send the message as is, with the filter name added to its `filterTags`
+    Tag,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChatFilter {
+    #[serde(flatten)]
+    pub kind: FilterKind,
+    pub action: FilterAction,
+}
+
+impl ChatFilter {
+    /// Checks a message against the filter, masking or tagging it if it matches.
+    /// Returns None if the filter didn't match, and false if the message should be dropped.
+    fn apply(&self, msg: &mut TwitchChatMessage) -> Option<bool> {
+        let masked = match &self.kind {
+            FilterKind::Bots { names } => {
+                let user = msg.user.user_name.to_lowercase();
+                let is_bot = if names.is_empty() {
+                    DEFAULT_BOTS.contains(&user.as_str())
+                } else {
+                    names.iter().any(|name| name.to_lowercase() == user)
+                };
+                is_bot.then(|| mask(&msg.message))
+            }
+            FilterKind::Commands { prefix } => msg
+                .message
+                .trim_start()
+                .starts_with(prefix.as_str())
+                .then(|| mask(&msg.message)),
+            FilterKind::BannedWords { words } => mask_words(&msg.message, |word| {
+                let word = word.to_lowercase();
+                words.iter().any(|banned| banned.to_lowercase() == word)
+            }),
+            FilterKind::Links => {
+                let trusted = msg
+                    .user_badges
+                    .iter()
+                    .any(|(badge, _)| LINK_BADGES.contains(&badge.as_str()));
+                if trusted {
+                    None
+                } else {
+                    mask_words(&msg.message, looks_like_link)
+                }
+            }
+            FilterKind::Zalgo => strip_zalgo(&msg.message),
+        }?;
+
+        match self.action {
+            FilterAction::Drop => return Some(false),
+            FilterAction::Mask => msg.message = masked,
+            FilterAction::Tag => msg.filter_tags.push(self.kind.name().to_string()),
+        }
+        Some(true)
+    }
+}
+
+/// Per-channel chat filters, applied to messages before they're sent out.
+/// Shared behind a read-write lock, so channels can filter messages at the same time.
+#[derive(Default)]
+pub struct ChatFilters {
+    /// Channel name -> filters, in the order they're applied
+    channels: HashMap<String, Vec<ChatFilter>>,
+    /// Channel name -> filter name -> messages matched. Only locked to count a match.
+    hits: Mutex<HashMap<String, HashMap<String, u64>>>,
+}
+
+impl ChatFilters {
+    pub fn new() -> Arc<RwLock<Self>> {
+        Arc::new(RwLock::new(Self::default()))
+    }
+
+    /// Loads filter configuration from the database
+    pub fn load(&mut self) -> OrchidResult<()> {
+        for config in select!(Vec<ChatFilterConfig>)? {
+            self.channels.insert(config.channel, config.filters);
+        }
+        debug!("Loaded chat filters for {} channels", self.channels.len());
+        Ok(())
+    }
+
+    pub fn get_filters(&self, channel: &str) -> Vec<ChatFilter> {
+        self.channels.get(channel).cloned().unwrap_or_default()
+    }
+
+    /// Replaces a channel's filters, persisting them
+    pub fn set_filters(&mut self, channel: &str, filters: Vec<ChatFilter>) -> OrchidResult<()> {
+        let channel = channel.to_lowercase();
+        execute!("DELETE FROM chatfilterconfig WHERE channel = ?", channel)?;
+        if filters.is_empty() {
+            self.channels.remove(&channel);
+            return Ok(());
+        }
+
+        ChatFilterConfig {
+            rowid: None,
+            channel: channel.clone(),
+            filters: filters.clone(),
+        }
+        .insert()?;
+        self.channels.insert(channel, filters);
+        Ok(())
+    }
+
+    /// How many messages each of a channel's filters matched since startup
+    pub fn get_hits(&self, channel: &str) -> HashMap<String, u64> {
+        self.hits
+            .lock()
+            .unwrap()
+            .get(channel)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Runs a message through its channel's filters. Returns false if the message should be dropped.
+    pub fn apply(&self, msg: &mut TwitchChatMessage) -> bool {
+        let Some(filters) = self.channels.get(&msg.channel) else {
+            return true;
+        };
+
+        for filter in filters {
+            let Some(keep) = filter.apply(msg) else {
+                continue;
+            };
+            *self
+                .hits
+                .lock()
+                .unwrap()
+                .entry(msg.channel.clone())
+                .or_default()
+                .entry(filter.kind.name().to_string())
+                .or_default() += 1;
+            if !keep {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+fn mask(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_whitespace() { c } else { '*' })
+        .collect()
+}
+
+/// Masks every word matching the predicate. Returns None if no words matched.
+fn mask_words(text: &str, matches: impl Fn(&str) -> bool) -> Option<String> {
+    let mut found = false;
+    let masked = text
+        .split(' ')
+        .map(|word| {
+            let trimmed = word.trim_matches(|c: char| !c.is_alphanumeric() && c != '/');
+            if !trimmed.is_empty() && matches(trimmed) {
+                found = true;
+                word.replace(trimmed, &mask(trimmed))
+            } else {
+                word.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    found.then_some(masked)
+}
+
+/// Whether a word looks like a URL or a bare domain (`example.com/path`)
+fn looks_like_link(word: &str) -> bool {
+    let word = word.to_lowercase();
+    if word.contains("://") || word.starts_with("www.") {
+        return true;
+    }
+    let host = word.split('/').next().unwrap_or_default();
+    match host.rsplit_once('.') {
+        Some((name, tld)) => {
+            !name.is_empty()
+                && (2..=6).contains(&tld.len())
+                && tld.chars().all(|c| c.is_ascii_alphabetic())
+        }
+        None => false,
+    }
+}
+
+fn is_combining_mark(c: char) -> bool {
+    matches!(c,
+        '\u{0300}'..='\u{036F}'
+        | '\u{1AB0}'..='\u{1AFF}'
+        | '\u{1DC0}'..='\u{1DFF}'
+        | '\u{20D0}'..='\u{20FF}'
+        | '\u{FE20}'..='\u{FE2F}')
+}
+
+/// Strips control characters and stacked combining marks. Returns None if there was nothing to strip.
+fn strip_zalgo(text: &str) -> Option<String> {
+    let mut stripped = String::with_capacity(text.len());
+    let mut marks = 0;
+    let mut found = false;
+    for c in text.chars() {
+        if c.is_control() {
+            found = true;
+            continue;
+        }
+        if is_combining_mark(c) {
+            marks += 1;
+            if marks > MAX_COMBINING_MARKS {
+                found = true;
+                continue;
+            }
+        } else {
+            marks = 0;
+        }
+        stripped.push(c);
+    }
+    found.then_some(stripped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_text_is_left_alone() {
+        assert_eq!(strip_zalgo("hello chat"), None);
+        assert_eq!(strip_zalgo(""), None);
+    }
+
+    #[test]
+    fn accents_are_kept() {
+        // Up to MAX_COMBINING_MARKS marks per character
+        assert_eq!(strip_zalgo("cafe\u{0301}"), None);
+        assert_eq!(strip_zalgo("a\u{0301}\u{0308}"), None);
+    }
+
+    #[test]
+    fn stacked_marks_are_stripped() {
+        assert_eq!(
+            strip_zalgo("h\u{0301}\u{0308}\u{0327}\u{0330}i"),
+            Some("h\u{0301}\u{0308}i".to_string())
+        );
+        // The count starts over for each character
+        assert_eq!(
+            strip_zalgo("a\u{0301}\u{0301}\u{0301}b\u{0301}\u{0301}"),
+            Some("a\u{0301}\u{0301}b\u{0301}\u{0301}".to_string())
+        );
+    }
+
+    #[test]
+    fn control_characters_are_stripped() {
+        assert_eq!(
+            strip_zalgo("hi\u{0007}\u{0000} there"),
+            Some("hi there".to_string())
+        );
+    }
+}
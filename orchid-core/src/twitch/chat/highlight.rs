@@ -0,0 +1,220 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tracing::debug;
+use turbosql::{execute, select, Turbosql};
+
+use super::{
+    message::{MessageHighlight, TwitchChatMessage},
+    processor::MessageProcessor,
+};
+use crate::{
+    db::HighlightRule,
+    err::{OrchidError, OrchidResult},
+};
+
+/// Author badges matched by a role rule that doesn't list its own
+const DEFAULT_ROLES: &[&str] = &["vip", "moderator"];
+
+/// What a highlight rule matches
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum HighlightKind {
+    /// Messages mentioning the broadcaster of the channel they were sent in
+    #[default]
+    BroadcasterMention,
+    /// Messages containing any of these words (case-insensitive)
+    Keywords { words: Vec<String> },
+    /// Messages matching a regular expression
+    Regex { pattern: String },
+    /// Messages from users with any of these badges. Defaults to VIPs and moderators if empty.
+    Role {
+        #[serde(default)]
+        badges: Vec<String>,
+    },
+    /// A user's first message in the channel
+    FirstTimeChatter,
+}
+
+/// How the chat box should style a highlighted message. All hints are optional.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HighlightStyle {
+    /// Accent color, e.g. for a border
+    pub color: Option<(u8, u8, u8)>,
+    pub background: Option<(u8, u8, u8)>,
+    /// Short label to show next to the message
+    pub label: Option<String>,
+}
+
+/// A rule with its regex compiled
+struct CompiledRule {
+    rule: HighlightRule,
+    regex: Option<Regex>,
+}
+
+impl CompiledRule {
+    fn new(rule: HighlightRule) -> OrchidResult<Self> {
+        let regex = match &rule.kind {
+            HighlightKind::Regex { pattern } => {
+                Some(Regex::new(pattern).map_err(|e| OrchidError::InvalidRule(e.to_string()))?)
+            }
+            _ => None,
+        };
+        Ok(Self { rule, regex })
+    }
+
+    fn matches(&self, msg: &TwitchChatMessage) -> bool {
+        if !self.rule.enabled {
+            return false;
+        }
+        if let Some(channel) = &self.rule.channel {
+            if *channel != msg.channel {
+                return false;
+            }
+        }
+
+        match &self.rule.kind {
+            HighlightKind::BroadcasterMention => words(&msg.message).any(|word| {
+                word.trim_start_matches('@')
+                    .eq_ignore_ascii_case(&msg.channel)
+            }),
+            HighlightKind::Keywords { words: keywords } => words(&msg.message).any(|word| {
+                keywords
+                    .iter()
+                    .any(|keyword| keyword.to_lowercase() == word.to_lowercase())
+            }),
+            HighlightKind::Regex { .. } => self
+                .regex
+                .as_ref()
+                .is_some_and(|regex| regex.is_match(&msg.message)),
+            HighlightKind::Role { badges } => msg.user_badges.iter().any(|(badge, _)| {
+                if badges.is_empty() {
+                    DEFAULT_ROLES.contains(&badge.as_str())
+                } else {
+                    badges.contains(badge)
+                }
+            }),
+            HighlightKind::FirstTimeChatter => msg.first_message,
+        }
+    }
+}
+
+/// Splits text into words, without surrounding punctuation
+fn words(text: &str) -> impl Iterator<Item = &str> {
+    text.split_whitespace()
+        .map(|word| word.trim_matches(|c: char| !c.is_alphanumeric() && c != '@' && c != '_'))
+        .filter(|word| !word.is_empty())
+}
+
+/// Highlight rules, stored in the database and evaluated for every message.
+/// Shared behind a read-write lock, so channels can evaluate rules at the same time.
+#[derive(Default)]
+pub struct HighlightRules {
+    rules: Vec<CompiledRule>,
+}
+
+impl HighlightRules {
+    pub fn new() -> Arc<RwLock<Self>> {
+        Arc::new(RwLock::new(Self::default()))
+    }
+
+    /// Loads rules from the database. Rules that no longer compile are skipped.
+    pub fn load(&mut self) -> OrchidResult<()> {
+        self.rules = select!(Vec<HighlightRule>)?
+            .into_iter()
+            .filter_map(|rule| CompiledRule::new(rule).ok())
+            .collect();
+        debug!("Loaded {} highlight rules", self.rules.len());
+        Ok(())
+    }
+
+    /// Gets all rules, or the rules applying to a channel
+    pub fn get_rules(&self, channel: Option<&str>) -> Vec<HighlightRule> {
+        self.rules
+            .iter()
+            .map(|compiled| &compiled.rule)
+            .filter(|rule| match (channel, &rule.channel) {
+                (Some(channel), Some(rule_channel)) => channel == rule_channel,
+                _ => true,
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Adds a rule, returning it with its ID
+    pub fn add_rule(&mut self, mut rule: HighlightRule) -> OrchidResult<HighlightRule> {
+        rule.rowid = None;
+        rule.channel = rule.channel.map(|channel| channel.to_lowercase());
+        let mut compiled = CompiledRule::new(rule)?;
+        compiled.rule.rowid = Some(compiled.rule.insert()?);
+
+        let rule = compiled.rule.clone();
+        self.rules.push(compiled);
+        Ok(rule)
+    }
+
+    pub fn update_rule(&mut self, id: i64, mut rule: HighlightRule) -> OrchidResult<HighlightRule> {
+        let index = self
+            .rules
+            .iter()
+            .position(|compiled| compiled.rule.rowid == Some(id))
+            .ok_or_else(|| OrchidError::NotFound(format!("Highlight rule {}", id)))?;
+
+        rule.rowid = Some(id);
+        rule.channel = rule.channel.map(|channel| channel.to_lowercase());
+        let compiled = CompiledRule::new(rule)?;
+        compiled.rule.update()?;
+
+        let rule = compiled.rule.clone();
+        self.rules[index] = compiled;
+        Ok(rule)
+    }
+
+    pub fn remove_rule(&mut self, id: i64) -> OrchidResult<()> {
+        let len = self.rules.len();
+        self.rules
+            .retain(|compiled| compiled.rule.rowid != Some(id));
+        if self.rules.len() == len {
+            return Err(OrchidError::NotFound(format!("Highlight rule {}", id)));
+        }
+        execute!("DELETE FROM highlightrule WHERE rowid = ?", id)?;
+        Ok(())
+    }
+
+    /// Gets the highlights for every rule a message matches
+    pub fn evaluate(&self, msg: &TwitchChatMessage) -> Vec<MessageHighlight> {
+        self.rules
+            .iter()
+            .filter(|compiled| compiled.matches(msg))
+            .filter_map(|compiled| {
+                Some(MessageHighlight {
+                    rule_id: compiled.rule.rowid?,
+                    style: compiled.rule.style.clone(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Tags messages with the highlight rules they match
+pub struct HighlightProcessor {
+    rules: Arc<RwLock<HighlightRules>>,
+}
+
+impl HighlightProcessor {
+    pub fn new(rules: Arc<RwLock<HighlightRules>>) -> Self {
+        Self { rules }
+    }
+}
+
+#[async_trait]
+impl MessageProcessor for HighlightProcessor {
+    async fn process(&self, msg: &mut TwitchChatMessage) -> bool {
+        msg.highlights = self.rules.read().await.evaluate(msg);
+        true
+    }
+}
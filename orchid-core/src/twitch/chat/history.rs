@@ -0,0 +1,276 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tracing::{debug, error};
+use turbosql::{execute, select, Turbosql};
+
+use super::{
+    message::{ModerationAction, TwitchChatMessage, TwitchInstructionMessage},
+    search::ChatSearchIndex,
+};
+use crate::{
+    db::{ChatLogInstruction, ChatLogMessage},
+    err::OrchidResult,
+};
+
+/// How often old history is pruned
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Page size when a query doesn't ask for one
+const DEFAULT_PAGE_SIZE: i64 = 100;
+const MAX_PAGE_SIZE: i64 = 1000;
+/// Bans, timeouts and chat clears only remove messages sent this long before them,
+/// like Twitch chat, which only clears what's still on screen
+const REMOVAL_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// How much chat history to keep. Anything past either limit is pruned.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetentionPolicy {
+    /// Drop messages older than this
+    pub max_age: Option<Duration>,
+    /// Keep at most this many messages (and moderation instructions)
+    pub max_rows: Option<i64>,
+}
+
+enum HistoryEntry {
+    Message(ChatLogMessage),
+    Instruction(ChatLogInstruction, ModerationAction),
+}
+
+/// Stores processed chat messages and moderation instructions in the database,
+/// and indexes messages for search if a search index is given.
+/// Messages removed by moderators are kept for review, but marked as deleted and hidden from search.
+/// Writes happen on a blocking thread so they never hold up chat.
+#[derive(Clone)]
+pub struct ChatHistory {
+    tx: UnboundedSender<HistoryEntry>,
+}
+
+impl ChatHistory {
+    /// Starts the history writer, and a task pruning history according to the retention policy
+    pub fn start(retention: RetentionPolicy, search: Option<ChatSearchIndex>) -> Self {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        let writer_search = search.clone();
+        tokio::task::spawn_blocking(move || {
+            while let Some(entry) = rx.blocking_recv() {
+                if let Err(e) = store(entry, writer_search.as_ref()) {
+                    error!("Failed to store chat history: {}", e);
+                }
+            }
+        });
+
+        if retention.max_age.is_some() || retention.max_rows.is_some() {
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    let search = search.clone();
+                    let result =
+                        tokio::task::spawn_blocking(move || prune(retention, search.as_ref()))
+                            .await;
+                    match result {
+                        Ok(Err(e)) => error!("Failed to prune chat history: {}", e),
+                        Err(e) => error!("Chat history pruning panicked: {}", e),
+                        Ok(Ok(())) => {}
+                    }
+                }
+            });
+        }
+
+        Self { tx }
+    }
+
+    pub fn record_message(&self, msg: &TwitchChatMessage) {
+        let entry = ChatLogMessage {
+            rowid: None,
+            channel: msg.channel.clone(),
+            channel_id: msg.channel_id.clone(),
+            user_id: msg.user.user_id.clone(),
+            user_name: msg.user.user_name.clone(),
+            message_id: msg.message_id.clone(),
+            timestamp: msg.timestamp_millis(),
+            message: msg.clone(),
+            deleted: false,
+        };
+        let _ = self.tx.send(HistoryEntry::Message(entry));
+    }
+
+    pub fn record_instruction(&self, instruction: &TwitchInstructionMessage) {
+        let entry = ChatLogInstruction {
+            rowid: None,
+            channel: instruction.channel.clone(),
+            msg_type: instruction.msg_type.clone(),
+            msg_subtype: instruction.msg_subtype.clone(),
+            associated_id: instruction.associated_id.clone(),
+            timestamp: instruction.timestamp_millis(),
+            action: instruction.action.as_str().to_string(),
+            target_login: instruction.target_login.clone(),
+            target_user_id: instruction.target_user_id.clone(),
+            timeout_duration: instruction.timeout_duration.map(|secs| secs as i64),
+        };
+        let _ = self
+            .tx
+            .send(HistoryEntry::Instruction(entry, instruction.action));
+    }
+}
+
+fn store(entry: HistoryEntry, search: Option<&ChatSearchIndex>) -> OrchidResult<()> {
+    match entry {
+        HistoryEntry::Message(msg) => {
+            let rowid = msg.insert()?;
+            if let Some(search) = search {
+                search.index(rowid, &msg)?;
+            }
+        }
+        HistoryEntry::Instruction(instruction, action) => {
+            instruction.insert()?;
+            remove_messages(&instruction, action)?;
+        }
+    }
+    Ok(())
+}
+
+/// Marks the messages a moderation event removed as deleted. Search hides them at query time.
+fn remove_messages(instruction: &ChatLogInstruction, action: ModerationAction) -> OrchidResult<()> {
+    let since = instruction.timestamp - REMOVAL_WINDOW.as_millis() as i64;
+    // Each is a single statement, so a removal is applied all at once or not at all
+    match action {
+        ModerationAction::Delete => {
+            execute!(
+                "UPDATE chatlogmessage SET deleted = true WHERE channel = ? AND message_id = ? AND NOT deleted",
+                instruction.channel,
+                instruction.associated_id
+            )?;
+        }
+        ModerationAction::Ban | ModerationAction::Timeout => {
+            execute!(
+                "UPDATE chatlogmessage SET deleted = true WHERE channel = ? AND user_id = ? AND timestamp BETWEEN ? AND ? AND NOT deleted",
+                instruction.channel,
+                instruction.associated_id,
+                since,
+                instruction.timestamp
+            )?;
+        }
+        ModerationAction::ClearChat => {
+            execute!(
+                "UPDATE chatlogmessage SET deleted = true WHERE channel = ? AND timestamp BETWEEN ? AND ? AND NOT deleted",
+                instruction.channel,
+                since,
+                instruction.timestamp
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn prune(retention: RetentionPolicy, search: Option<&ChatSearchIndex>) -> OrchidResult<()> {
+    if let Some(max_age) = retention.max_age {
+        let cutoff = Utc::now().timestamp_millis() - max_age.as_millis() as i64;
+        execute!("DELETE FROM chatlogmessage WHERE timestamp < ?", cutoff)?;
+        execute!("DELETE FROM chatloginstruction WHERE timestamp < ?", cutoff)?;
+        if let Some(search) = search {
+            search.prune_before(cutoff)?;
+        }
+    }
+    if let Some(max_rows) = retention.max_rows {
+        execute!(
+            "DELETE FROM chatlogmessage WHERE rowid <= (SELECT rowid FROM chatlogmessage ORDER BY rowid DESC LIMIT 1 OFFSET ?)",
+            max_rows
+        )?;
+        execute!(
+            "DELETE FROM chatloginstruction WHERE rowid <= (SELECT rowid FROM chatloginstruction ORDER BY rowid DESC LIMIT 1 OFFSET ?)",
+            max_rows
+        )?;
+        if let Some(search) = search {
+            search.prune_to(max_rows)?;
+        }
+    }
+    debug!("Pruned chat history");
+    Ok(())
+}
+
+/// Filters for querying history. All filters are optional.
+/// Results are newest first; pass the returned cursor as `before` to get the next page.
+#[derive(Debug, Default, Deserialize)]
+pub struct HistoryQuery {
+    /// Channel name (login)
+    pub channel: Option<String>,
+    /// User login or ID
+    pub user: Option<String>,
+    /// Only entries at or after this time, in unix milliseconds
+    pub from: Option<i64>,
+    /// Only entries at or before this time, in unix milliseconds
+    pub to: Option<i64>,
+    /// Cursor from a previous page
+    pub before: Option<i64>,
+    pub limit: Option<i64>,
+    /// Include messages removed by moderators. Defaults to false.
+    #[serde(default)]
+    pub include_deleted: bool,
+}
+
+impl HistoryQuery {
+    fn limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE)
+    }
+}
+
+/// A page of history
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryPage<T> {
+    pub entries: Vec<T>,
+    /// Cursor for the next (older) page, if there is one
+    pub next_cursor: Option<i64>,
+}
+
+impl<T> HistoryPage<T> {
+    fn new(entries: Vec<T>, limit: i64, rowid: impl Fn(&T) -> Option<i64>) -> Self {
+        let next_cursor = if entries.len() as i64 == limit {
+            entries.last().and_then(rowid)
+        } else {
+            None
+        };
+        Self {
+            entries,
+            next_cursor,
+        }
+    }
+}
+
+/// Looks up stored chat messages
+pub fn query_messages(query: &HistoryQuery) -> OrchidResult<HistoryPage<ChatLogMessage>> {
+    let limit = query.limit();
+    let channel = query.channel.as_ref().map(|c| c.to_lowercase());
+    let user = query.user.as_ref().map(|u| u.to_lowercase());
+    let entries = select!(Vec<ChatLogMessage> "WHERE (? IS NULL OR channel = ?) AND (? IS NULL OR user_name = ? OR user_id = ?) AND (? IS NULL OR timestamp >= ?) AND (? IS NULL OR timestamp <= ?) AND (? IS NULL OR rowid < ?) AND (? OR NOT deleted) ORDER BY rowid DESC LIMIT ?",
+        channel, channel,
+        user, user, user,
+        query.from, query.from,
+        query.to, query.to,
+        query.before, query.before,
+        query.include_deleted,
+        limit
+    )?;
+    Ok(HistoryPage::new(entries, limit, |entry| entry.rowid))
+}
+
+/// Looks up stored moderation events. The user filter matches the affected user's login or ID, or a message ID.
+pub fn query_instructions(query: &HistoryQuery) -> OrchidResult<HistoryPage<ChatLogInstruction>> {
+    let limit = query.limit();
+    let channel = query.channel.as_ref().map(|c| c.to_lowercase());
+    let user = query.user.as_ref().map(|u| u.to_lowercase());
+    let entries = select!(Vec<ChatLogInstruction> "WHERE (? IS NULL OR channel = ?) AND (? IS NULL OR target_login = ? OR target_user_id = ? OR associated_id = ?) AND (? IS NULL OR timestamp >= ?) AND (? IS NULL OR timestamp <= ?) AND (? IS NULL OR rowid < ?) ORDER BY rowid DESC LIMIT ?",
+        channel, channel,
+        user, user, user, query.user,
+        query.from, query.from,
+        query.to, query.to,
+        query.before, query.before,
+        limit
+    )?;
+    Ok(HistoryPage::new(entries, limit, |entry| entry.rowid))
+}
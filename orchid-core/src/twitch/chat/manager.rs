@@ -1,9 +1,18 @@
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use twitch_irc::login::StaticLoginCredentials;
-use twitch_irc::transport::tcp::{TCPTransport, TLS};
-use twitch_irc::TwitchIRCClient;
+use tracing::error;
+use turbosql::{execute, select, Turbosql};
+
+use super::message::FeedLabel;
+use super::source::ChatSourceHandle;
+use super::username_to_color;
+use crate::db::ChatFeed;
+use crate::err::{OrchidError, OrchidResult};
+use crate::twitch::emote::{EmoteHandler, EmoteScope};
+
+/// Prefix of the pseudo client ids that feeds subscribe to their channels with
+const FEED_PREFIX: &str = "feed:";
 
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 pub struct ChannelSubscription {
@@ -12,10 +21,21 @@ pub struct ChannelSubscription {
     pub client_id: String,
 }
 
+/// Clients subscribed to a channel through a feed, and how the channel is labelled in it
+#[derive(Debug, Clone)]
+pub struct FeedSubscription {
+    pub label: FeedLabel,
+    pub clients: HashSet<String>,
+}
+
 pub struct SubscriptionManager {
     subscriptions: HashMap<String, HashSet<String>>, // channel -> set of client_ids
     client_channels: HashMap<String, HashSet<String>>, // client_id -> set of channels
-    chat_client: Option<TwitchIRCClient<TCPTransport<TLS>, StaticLoginCredentials>>,
+    feeds: HashMap<String, ChatFeed>,                // feed name -> feed
+    feed_clients: HashMap<String, HashSet<String>>,  // feed name -> set of client_ids
+    chat_client: Option<Box<dyn ChatSourceHandle>>,
+    /// Emotes to prefetch when a channel is joined
+    emotes: Option<Arc<EmoteHandler>>,
 }
 
 impl SubscriptionManager {
@@ -23,14 +43,29 @@ impl SubscriptionManager {
         Arc::new(Mutex::new(Self {
             subscriptions: HashMap::new(),
             client_channels: HashMap::new(),
+            feeds: HashMap::new(),
+            feed_clients: HashMap::new(),
             chat_client: None,
+            emotes: None,
         }))
     }
 
-    pub fn set_chat_client(
-        &mut self,
-        client: TwitchIRCClient<TCPTransport<TLS>, StaticLoginCredentials>,
-    ) {
+    /// Sets the emote handler, prefetching global emotes and those of channels subscribed to before it was set.
+    pub fn set_emote_handler(&mut self, emotes: Arc<EmoteHandler>) {
+        emotes.prefetch(&EmoteScope::default());
+        for channel in self.subscriptions.keys() {
+            emotes.prefetch(&emotes.channel_scope(channel));
+        }
+        self.emotes = Some(emotes);
+    }
+
+    /// Sets the chat source handle, joining any channels subscribed to before it was set.
+    pub fn set_chat_client(&mut self, client: Box<dyn ChatSourceHandle>) {
+        for channel in self.subscriptions.keys() {
+            if let Err(e) = client.join(channel.clone()) {
+                error!("Failed to join {}: {}", channel, e);
+            }
+        }
         self.chat_client = Some(client);
     }
 
@@ -51,7 +86,12 @@ impl SubscriptionManager {
             .or_default()
             .insert(channel.clone());
 
-        // If this is a new channel, join it in Twitch chat
+        // If this is a new channel, fetch its emotes and join it in Twitch chat
+        if self.subscriptions[&channel].len() == 1 {
+            if let Some(emotes) = &self.emotes {
+                emotes.prefetch(&emotes.channel_scope(&channel));
+            }
+        }
         if let Some(chat_client) = &self.chat_client {
             if self.subscriptions[&channel].len() == 1 {
                 chat_client.join(channel)?;
@@ -83,8 +123,46 @@ impl SubscriptionManager {
         }
     }
 
+    /// Fetches the emotes of a channel we've joined by its ID, once the server tells us the ID.
+    /// Emotes looked up by channel name are fetched when the channel is subscribed to.
+    pub fn room_joined(&self, channel: &str, channel_id: &str) {
+        if let Some(emotes) = &self.emotes {
+            emotes.prefetch(&EmoteScope {
+                channel_name: channel.to_string(),
+                channel_id: channel_id.to_string(),
+                ..Default::default()
+            });
+        }
+    }
+
+    /// Gets the clients directly subscribed to a channel. Feed subscribers are not included.
     pub fn get_channel_subscribers(&self, channel: &str) -> HashSet<String> {
-        self.subscriptions.get(channel).cloned().unwrap_or_default()
+        self.subscriptions
+            .get(channel)
+            .map(|clients| {
+                clients
+                    .iter()
+                    .filter(|id| !id.starts_with(FEED_PREFIX))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Gets the clients subscribed to a channel through feeds, with the channel's label in each feed.
+    pub fn get_channel_feeds(&self, channel: &str) -> Vec<FeedSubscription> {
+        let Some(clients) = self.subscriptions.get(channel) else {
+            return vec![];
+        };
+        clients
+            .iter()
+            .filter_map(|id| id.strip_prefix(FEED_PREFIX))
+            .filter_map(|name| {
+                let label = feed_label(self.feeds.get(name)?, channel)?;
+                let clients = self.feed_clients.get(name).cloned().unwrap_or_default();
+                Some(FeedSubscription { label, clients })
+            })
+            .collect()
     }
 
     pub fn get_client_subscriptions(&self, client_id: &str) -> HashSet<String> {
@@ -94,7 +172,119 @@ impl SubscriptionManager {
             .unwrap_or_default()
     }
 
+    /// Loads persisted feeds from the database
+    pub fn load_feeds(&mut self) -> OrchidResult<()> {
+        for feed in select!(Vec<ChatFeed>)? {
+            self.feeds.insert(feed.name.clone(), feed);
+        }
+        Ok(())
+    }
+
+    pub fn get_feeds(&self) -> Vec<ChatFeed> {
+        self.feeds.values().cloned().collect()
+    }
+
+    /// Creates or replaces a feed, persisting it. Live subscribers are moved to the new channel list.
+    pub async fn set_feed(&mut self, feed: ChatFeed) -> OrchidResult<()> {
+        execute!("DELETE FROM chatfeed WHERE name = ?", feed.name)?;
+        feed.insert()?;
+
+        let feed_id = format!("{}{}", FEED_PREFIX, feed.name);
+        let old_channels = self.get_client_subscriptions(&feed_id);
+        let new_channels: HashSet<String> =
+            feed.channels.iter().map(|c| c.channel.clone()).collect();
+
+        if self.feed_clients.contains_key(&feed.name) {
+            for channel in old_channels.difference(&new_channels) {
+                self.unsubscribe(channel, &feed_id).await;
+            }
+            for channel in new_channels.difference(&old_channels) {
+                self.subscribe(channel.clone(), feed_id.clone())
+                    .await
+                    .map_err(|e| OrchidError::ChannelError(e.to_string()))?;
+            }
+        }
+
+        self.feeds.insert(feed.name.clone(), feed);
+        Ok(())
+    }
+
+    /// Deletes a feed, dropping any subscribers it had.
+    pub async fn remove_feed(&mut self, name: &str) -> OrchidResult<()> {
+        if self.feeds.remove(name).is_none() {
+            return Err(OrchidError::NotFound(format!("feed {}", name)));
+        }
+        execute!("DELETE FROM chatfeed WHERE name = ?", name)?;
+
+        let feed_id = format!("{}{}", FEED_PREFIX, name);
+        self.feed_clients.remove(name);
+        self.remove_client(&feed_id);
+        Ok(())
+    }
+
+    /// Subscribes a client to every channel in a feed.
+    pub async fn subscribe_feed(&mut self, name: &str, client_id: String) -> OrchidResult<()> {
+        let Some(feed) = self.feeds.get(name) else {
+            return Err(OrchidError::NotFound(format!("feed {}", name)));
+        };
+        let channels: Vec<String> = feed.channels.iter().map(|c| c.channel.clone()).collect();
+
+        let clients = self.feed_clients.entry(name.to_string()).or_default();
+        clients.insert(client_id);
+
+        // First subscriber, subscribe the feed itself to its channels
+        if clients.len() == 1 {
+            let feed_id = format!("{}{}", FEED_PREFIX, name);
+            for channel in channels {
+                self.subscribe(channel, feed_id.clone())
+                    .await
+                    .map_err(|e| OrchidError::ChannelError(e.to_string()))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn unsubscribe_feed(&mut self, name: &str, client_id: &str) {
+        if let Some(clients) = self.feed_clients.get_mut(name) {
+            clients.remove(client_id);
+            if clients.is_empty() {
+                self.feed_clients.remove(name);
+                self.remove_client(&format!("{}{}", FEED_PREFIX, name));
+            }
+        }
+    }
+
+    pub fn get_feed_subscribers(&self, name: &str) -> HashSet<String> {
+        self.feed_clients.get(name).cloned().unwrap_or_default()
+    }
+
+    pub fn get_feed(&self, name: &str) -> Option<&ChatFeed> {
+        self.feeds.get(name)
+    }
+
+    /// Gets the names of the feeds a client is subscribed to
+    pub fn get_client_feeds(&self, client_id: &str) -> Vec<String> {
+        self.feed_clients
+            .iter()
+            .filter(|(_, clients)| clients.contains(client_id))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
     pub fn remove_client(&mut self, client_id: &str) {
+        // Drop any feed subscriptions, unsubscribing feeds nobody is listening to anymore
+        let empty_feeds: Vec<String> = self
+            .feed_clients
+            .iter_mut()
+            .filter_map(|(name, clients)| {
+                (clients.remove(client_id) && clients.is_empty()).then(|| name.clone())
+            })
+            .collect();
+        for name in empty_feeds {
+            self.feed_clients.remove(&name);
+            self.remove_client(&format!("{}{}", FEED_PREFIX, name));
+        }
+
         // Get all channels this client was subscribed to
         if let Some(channels) = self.client_channels.remove(client_id) {
             // Remove client from each channel's subscription list
@@ -113,3 +303,13 @@ impl SubscriptionManager {
         }
     }
 }
+
+/// Builds the label for a channel within a feed, or `None` if the channel isn't in the feed.
+pub fn feed_label(feed: &ChatFeed, channel: &str) -> Option<FeedLabel> {
+    let entry = feed.channels.iter().find(|c| c.channel == channel)?;
+    Some(FeedLabel {
+        feed: feed.name.clone(),
+        label: entry.label.clone().unwrap_or_else(|| channel.to_string()),
+        color: entry.color.unwrap_or_else(|| username_to_color(channel)),
+    })
+}
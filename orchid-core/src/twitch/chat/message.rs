@@ -1,8 +1,11 @@
 use serde::{Deserialize, Serialize};
+use twitch_irc::message::IRCTags;
 
+use super::highlight::HighlightStyle;
 use super::{triple_to_rgbcolor, username_to_color};
+use crate::twitch::emote::{Emote, EmoteScope, EmoteSetChange};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct TwitchChatMessage {
     pub msg_type: String,
@@ -14,14 +17,156 @@ pub struct TwitchChatMessage {
     pub user: TwitchChatUser,
     /// User's current badges (name, URL)
     pub user_badges: Vec<(String, String)>,
+    /// Name color, corrected for readability on the overlay
     pub nickname_color: (u8, u8, u8),
+    /// The name color the user picked on Twitch, if any
+    #[serde(default)]
+    pub original_color: Option<(u8, u8, u8)>,
     /// The message, with Discord-esque emote formatting
     pub message: String,
     pub message_id: String,
     pub server_timestamp: String,
+    /// Where the message originally came from, if it was relayed through a Shared Chat session
+    pub shared_chat: Option<SharedChatOrigin>,
+    /// The feed this message was delivered through, if the client subscribed to a merged feed
+    pub feed: Option<FeedLabel>,
+    /// Names of the chat filters that tagged this message
+    #[serde(default)]
+    pub filter_tags: Vec<String>,
+    /// Whether this is the user's first message in the channel (`first-msg`)
+    #[serde(default)]
+    pub first_message: bool,
+    /// Whether Twitch considers the user a returning chatter (`returning-chatter`)
+    #[serde(default)]
+    pub returning_chatter: bool,
+    /// Whether we haven't seen the user chat in this channel before
+    #[serde(default)]
+    pub new_chatter: bool,
+    /// Highlight rules this message matched
+    #[serde(default)]
+    pub highlights: Vec<MessageHighlight>,
+    /// `@mentions` in the message, with who they refer to
+    #[serde(default)]
+    pub mentions: Vec<MessageMention>,
+    /// Emotes in the message, with the modifier and zero-width emotes that follow each stacked on it
+    #[serde(default)]
+    pub emotes: Vec<MessageEmote>,
 }
+
+/// Sent to a channel's subscribers when someone chats there for the first time, so overlays can welcome them
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
+pub struct NewChatterEvent {
+    /// Always `NEW_CHATTER`
+    pub msg_type: String,
+    /// The channel name they chatted in
+    pub channel: String,
+    pub user: TwitchChatUser,
+    pub nickname_color: (u8, u8, u8),
+    /// Their first message
+    pub message_id: String,
+}
+
+impl NewChatterEvent {
+    pub fn new(msg: &TwitchChatMessage) -> Self {
+        Self {
+            msg_type: "NEW_CHATTER".to_string(),
+            channel: msg.channel.clone(),
+            user: msg.user.clone(),
+            nickname_color: msg.nickname_color,
+            message_id: msg.message_id.clone(),
+        }
+    }
+}
+
+/// Sent to a channel's subscribers when its emotes from a provider changed, so overlays can reload them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EmoteSetChangedEvent {
+    /// Always `EMOTE_SET_CHANGED`
+    pub msg_type: String,
+    pub channel: String,
+    /// `7tv`, `bttv`, `ffz` or `twitch`
+    pub provider: String,
+}
+
+impl EmoteSetChangedEvent {
+    pub fn new(change: &EmoteSetChange) -> Self {
+        Self {
+            msg_type: "EMOTE_SET_CHANGED".to_string(),
+            channel: change.channel.clone(),
+            provider: change.provider.to_string(),
+        }
+    }
+}
+
+/// A highlight rule a message matched, and how to style it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageHighlight {
+    pub rule_id: i64,
+    pub style: HighlightStyle,
+}
+
+/// An emote used in a message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageEmote {
+    /// Which whitespace-separated word of the original message it is, counting from 0.
+    /// Its modifiers are the words right after it.
+    pub word: usize,
+    #[serde(flatten)]
+    pub emote: Emote,
+    /// Modifier and zero-width emotes applied to this one, in the order they were sent
+    pub modifiers: Vec<Emote>,
+}
+
+/// An `@mention` of another chatter within a message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageMention {
+    /// Where the mention starts in the message, in UTF-16 code units (as indexed by JavaScript strings)
+    pub start: usize,
+    /// Where the mention ends, exclusive
+    pub end: usize,
+    /// The mentioned login, lowercased
+    pub login: String,
+    /// The mentioned user's ID and display name, if they've chatted before
+    pub user_id: Option<String>,
+    pub display_name: Option<String>,
+    /// The mentioned user's last name color, or the fallback color for the login, made readable
+    pub color: (u8, u8, u8),
+}
+
+/// How a message's channel is labelled within a merged feed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FeedLabel {
+    /// The feed name
+    pub feed: String,
+    /// Label for the channel the message was sent in
+    pub label: String,
+    pub color: (u8, u8, u8),
+}
+
+/// Origin of a message relayed from another channel in a Shared Chat session
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SharedChatOrigin {
+    /// The channel ID the message was originally sent in (`source-room-id`)
+    pub room_id: String,
+    /// The channel name the message was originally sent in, if we know it
+    pub room_login: Option<String>,
+    /// The message ID in the originating channel (`source-id`)
+    pub message_id: String,
+    /// User's badges in the originating channel (name, version)
+    pub badges: Vec<(String, String)>,
+    /// Whether the message was only sent to the originating channel (`source-only`)
+    pub source_only: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct TwitchChatUser {
     pub user_id: String,
     /// The user's "Login" name
@@ -34,8 +179,45 @@ pub struct TwitchChatUser {
 #[serde(rename_all = "camelCase")]
 pub struct TwitchInstructionMessage {
     pub msg_type: String,
+    /// The channel name the instruction applies to
+    pub channel: String,
     pub msg_subtype: String,
+    /// The user ID (CLEARCHAT) or message ID (CLEARMSG) the instruction applies to
     pub associated_id: String,
+    /// What the moderator did
+    pub action: ModerationAction,
+    /// Login of the user whose messages were removed
+    pub target_login: Option<String>,
+    /// ID of the user whose messages were removed
+    pub target_user_id: Option<String>,
+    /// Timeout length in seconds
+    pub timeout_duration: Option<u64>,
+    /// When the moderator acted, as sent by the server (`tmi-sent-ts`)
+    #[serde(default)]
+    pub server_timestamp: String,
+}
+
+/// A moderation action behind a TwitchInstructionMessage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationAction {
+    /// The whole chat was cleared
+    ClearChat,
+    Ban,
+    Timeout,
+    /// A single message was deleted
+    Delete,
+}
+
+impl ModerationAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ModerationAction::ClearChat => "clear_chat",
+            ModerationAction::Ban => "ban",
+            ModerationAction::Timeout => "timeout",
+            ModerationAction::Delete => "delete",
+        }
+    }
 }
 
 // twitch_irc ServerMessage to TwitchChatMessage
@@ -56,6 +238,7 @@ impl TryFrom<twitch_irc::message::PrivmsgMessage> for TwitchChatMessage {
             .into_iter()
             .map(|badge| (badge.name.to_string(), badge.version.to_string()))
             .collect();
+        let original_color = msg.name_color.map(|color| (color.r, color.g, color.b));
         let color = msg
             .name_color
             .unwrap_or(triple_to_rgbcolor(username_to_color(&user.user_name)));
@@ -64,6 +247,14 @@ impl TryFrom<twitch_irc::message::PrivmsgMessage> for TwitchChatMessage {
         let message = msg.message_text.to_string();
         let message_id = msg.message_id.to_string();
         let server_timestamp = msg.server_timestamp.to_string();
+        let shared_chat = SharedChatOrigin::from_tags(&msg.source.tags, &channel_id);
+        let first_message = msg.source.tags.0.get("first-msg").is_some_and(|v| v == "1");
+        let returning_chatter = msg
+            .source
+            .tags
+            .0
+            .get("returning-chatter")
+            .is_some_and(|v| v == "1");
 
         Ok(TwitchChatMessage {
             msg_type,
@@ -72,9 +263,108 @@ impl TryFrom<twitch_irc::message::PrivmsgMessage> for TwitchChatMessage {
             user,
             user_badges,
             nickname_color,
+            original_color,
             message,
             message_id,
             server_timestamp,
+            shared_chat,
+            feed: None,
+            filter_tags: vec![],
+            first_message,
+            returning_chatter,
+            new_chatter: false,
+            highlights: vec![],
+            mentions: vec![],
+            emotes: vec![],
+        })
+    }
+}
+
+impl TwitchChatMessage {
+    /// When the message was sent, in unix milliseconds. Falls back to now if the timestamp doesn't parse.
+    pub fn timestamp_millis(&self) -> i64 {
+        server_timestamp_millis(&self.server_timestamp)
+    }
+
+    /// Whether this message was relayed from another channel in a Shared Chat session
+    pub fn is_shared(&self) -> bool {
+        self.shared_chat.is_some()
+    }
+
+    /// The channel ID the message was originally sent in.
+    /// Same as `channel_id` unless the message came through Shared Chat.
+    pub fn origin_channel_id(&self) -> &str {
+        match &self.shared_chat {
+            Some(origin) => origin.room_id.as_str(),
+            None => self.channel_id.as_str(),
+        }
+    }
+
+    /// The channel name the message was originally sent in.
+    /// Empty if the message came through Shared Chat from a channel we haven't seen yet.
+    pub fn origin_channel(&self) -> &str {
+        match &self.shared_chat {
+            Some(origin) => origin.room_login.as_deref().unwrap_or_default(),
+            None => self.channel.as_str(),
+        }
+    }
+
+    /// Scope to use when looking up emotes for this message
+    pub fn emote_scope(&self) -> EmoteScope {
+        EmoteScope {
+            user_id: self.user.user_id.clone(),
+            user_name: self.user.user_name.clone(),
+            channel_id: self.origin_channel_id().to_string(),
+            channel_name: self.origin_channel().to_string(),
+        }
+    }
+}
+
+impl TwitchInstructionMessage {
+    /// When the moderator acted, in unix milliseconds. Falls back to now if the timestamp doesn't parse.
+    pub fn timestamp_millis(&self) -> i64 {
+        server_timestamp_millis(&self.server_timestamp)
+    }
+}
+
+/// Parses a `server_timestamp` into unix milliseconds, falling back to now
+fn server_timestamp_millis(server_timestamp: &str) -> i64 {
+    chrono::NaiveDateTime::parse_from_str(
+        server_timestamp.trim_end_matches(" UTC"),
+        "%Y-%m-%d %H:%M:%S%.f",
+    )
+    .map(|time| time.and_utc().timestamp_millis())
+    .unwrap_or_else(|_| chrono::Utc::now().timestamp_millis())
+}
+
+impl SharedChatOrigin {
+    /// Reads the Shared Chat `source-*` tags off a message.
+    /// Returns `None` if the message was sent in the channel it was received in.
+    pub fn from_tags(tags: &IRCTags, channel_id: &str) -> Option<Self> {
+        let tag = |key: &str| tags.0.get(key).filter(|v| !v.is_empty());
+
+        let room_id = tag("source-room-id")?;
+        // Twitch also tags messages sent in our own channel during a session
+        if room_id == channel_id {
+            return None;
+        }
+
+        let badges = tag("source-badges")
+            .map(|badges| {
+                badges
+                    .split(',')
+                    .filter_map(|badge| badge.split_once('/'))
+                    .map(|(name, version)| (name.to_string(), version.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Some(SharedChatOrigin {
+            room_id: room_id.to_string(),
+            room_login: None,
+            message_id: tag("source-id").cloned().unwrap_or_default(),
+            badges,
+            source_only: tag("source-only").is_some_and(|v| v == "1" || v == "true"),
         })
     }
 }
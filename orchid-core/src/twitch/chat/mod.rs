@@ -1,8 +1,15 @@
-use std::{collections::HashSet, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
-use message::{TwitchChatMessage, TwitchInstructionMessage};
-use tokio::sync::{mpsc::UnboundedReceiver, Mutex};
-use tracing::{error, warn};
+use message::{FeedLabel, NewChatterEvent, TwitchChatMessage, TwitchInstructionMessage};
+use tokio::sync::{
+    mpsc::{self, UnboundedReceiver, UnboundedSender},
+    Mutex,
+};
+use tracing::warn;
 // what the heck twitch chat!!
 use twitch_irc::{
     login::{LoginCredentials, StaticLoginCredentials},
@@ -13,127 +20,255 @@ use twitch_irc::{
 
 use crate::ws::{WebsocketCollection, WsMessage};
 
+use backfill::RecentMessages;
+use history::ChatHistory;
 use manager::SubscriptionManager;
+use moderation::ModerationLog;
+use processor::ProcessorChain;
+use source::{ChatEvent, ChatSource};
 
-use super::emote::EmoteHandler;
-
+pub mod backfill;
+pub mod chatters;
+pub mod color;
+pub mod filter;
+pub mod highlight;
+pub mod history;
 pub mod manager;
 pub mod message;
+pub mod moderation;
+pub mod processor;
+pub mod search;
+pub mod source;
 
-pub async fn setup_twitch_chat(
-    state: Arc<Mutex<WebsocketCollection>>,
-    sub_manager: Arc<Mutex<SubscriptionManager>>,
-    emote_manager: Arc<Mutex<EmoteHandler>>,
-) {
-    let chat = TwitchChatClient::<StaticLoginCredentials>::new();
-    let (chat, mut receiver) = chat.get_pair().await;
+/// How long a channel worker waits for events before stopping
+const WORKER_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Everything needed to process chat and send it out, shared by the per-channel workers
+#[derive(Clone)]
+pub struct ChatPipeline {
+    pub state: Arc<Mutex<WebsocketCollection>>,
+    pub sub_manager: Arc<Mutex<SubscriptionManager>>,
+    pub processors: Arc<ProcessorChain>,
+    pub recent_messages: Arc<Mutex<RecentMessages>>,
+    pub history: Option<ChatHistory>,
+}
+
+pub async fn setup_twitch_chat(source: Box<dyn ChatSource>, pipeline: ChatPipeline) {
+    let (chat, mut receiver) = source.start().await;
 
     // Store chat client in subscription manager
-    sub_manager.lock().await.set_chat_client(chat);
+    pipeline.sub_manager.lock().await.set_chat_client(chat);
 
     let join_handle = tokio::spawn(async move {
-        while let Some(message) = receiver.recv().await {
-            match message {
-                ServerMessage::Privmsg(msg) => {
-                    let channel = msg.channel_login.as_str();
-
-                    // Get subscribers for this channel
-                    let subscribers = sub_manager.lock().await.get_channel_subscribers(channel);
-
-                    // format message in our own format if it is a privmsg
-                    match TwitchChatMessage::try_from(msg.to_owned()) {
-                        Ok(mut msg) => {
-                            // postprocess message with emote parsing
-                            {
-                                let mut emote_manager = emote_manager.lock().await;
-                                msg.message = emote_manager
-                                    .process_message_with_emotes(
-                                        &msg.message,
-                                        msg.user.user_name.as_str(),
-                                        msg.channel.as_str(),
-                                    )
-                                    .await;
-                            }
-                            let json = serde_json::to_string(&msg).unwrap();
-                            send_twitchchat_msg_to_subscribers(state.clone(), subscribers, json)
-                                .await;
-                        }
-                        Err(e) => {
-                            error!("Error converting message to TwitchChatMessage: {:?}", e);
-                            continue;
-                        }
+        // Channel ID -> channel name, for labelling Shared Chat messages
+        let mut known_rooms: HashMap<String, String> = HashMap::new();
+        // Channel name -> worker processing that channel's events
+        let mut workers: HashMap<String, UnboundedSender<ChatEvent>> = HashMap::new();
+
+        while let Some(mut event) = receiver.recv().await {
+            let channel = match &mut event {
+                ChatEvent::Message(msg) => {
+                    known_rooms
+                        .entry(msg.channel_id.clone())
+                        .or_insert_with(|| msg.channel.clone());
+                    if let Some(origin) = msg.shared_chat.as_mut() {
+                        origin.room_login = known_rooms.get(&origin.room_id).cloned();
                     }
+                    msg.channel.clone()
                 }
-                ServerMessage::ClearChat(msg) => {
-                    // get subscribers for this channel
-                    let subscribers = sub_manager
-                        .lock()
-                        .await
-                        .get_channel_subscribers(msg.channel_login.as_str());
-
-                    // Parse message into own format
-                    let obj = match msg.action {
-                        // Request to clear chat
-                        twitch_irc::message::ClearChatAction::ChatCleared => {
-                            &TwitchInstructionMessage {
-                                msg_type: "CLEARCHAT".to_string(),
-                                msg_subtype: "CLEAR_CHAT".to_string(),
-                                associated_id: "".to_string(),
-                            }
-                        }
-                        // The below two are when a user is removed from chat.
-                        // I think the Twitch API just sends their messages once they are unbanned.
-                        // Should be fine.
-                        twitch_irc::message::ClearChatAction::UserBanned {
-                            user_login: _,
-                            user_id,
-                        } => &TwitchInstructionMessage {
-                            msg_type: "CLEARCHAT".to_string(),
-                            msg_subtype: "REMOVE_USER_MESSAGES".to_string(),
-                            associated_id: user_id.to_string(),
-                        },
-                        twitch_irc::message::ClearChatAction::UserTimedOut {
-                            user_login: _,
-                            user_id,
-                            timeout_length: _,
-                        } => &TwitchInstructionMessage {
-                            msg_type: "CLEARCHAT".to_string(),
-                            msg_subtype: "REMOVE_USER_MESSAGES".to_string(),
-                            associated_id: user_id.to_string(),
-                        },
-                    };
-
-                    let json = serde_json::to_string(obj).unwrap();
-                    send_twitchchat_msg_to_subscribers(state.clone(), subscribers, json).await;
+                ChatEvent::Instruction(obj) => obj.channel.clone(),
+                ChatEvent::Notice { channel, message } => {
+                    // Print out to console (warn)
+                    warn!("Channel {:?} sent NOTICE: {}", channel, message);
+                    continue;
                 }
-                ServerMessage::ClearMsg(msg) => {
-                    let obj = TwitchInstructionMessage {
-                        msg_type: "CLEARMSG".to_string(),
-                        msg_subtype: "SINGLE".to_string(),
-                        associated_id: msg.message_id.to_string(),
-                    };
-                    let subscribers = sub_manager
+                ChatEvent::RoomState {
+                    channel,
+                    channel_id,
+                } => {
+                    known_rooms.insert(channel_id.clone(), channel.clone());
+                    pipeline
+                        .sub_manager
                         .lock()
                         .await
-                        .get_channel_subscribers(msg.channel_login.as_str());
-                    let json = serde_json::to_string(&obj).unwrap();
-                    send_twitchchat_msg_to_subscribers(state.clone(), subscribers, json).await;
+                        .room_joined(channel, channel_id);
+                    continue;
                 }
-                ServerMessage::Notice(msg) => {
-                    // Print out to console (warn)
-                    warn!(
-                        "Channel {:?} sent NOTICE: {}",
-                        msg.channel_login, msg.message_text
-                    );
-                }
-                _ => {}
-            }
+            };
+
+            // Workers stop once their channel goes quiet, e.g. after it's parted
+            let event = match workers.get(&channel) {
+                Some(worker) => match worker.send(event) {
+                    Ok(()) => continue,
+                    Err(mpsc::error::SendError(event)) => event,
+                },
+                None => event,
+            };
+            workers.retain(|_, worker| !worker.is_closed());
+            let (tx, rx) = mpsc::unbounded_channel();
+            tokio::spawn(run_channel(pipeline.clone(), rx));
+            let _ = tx.send(event);
+            workers.insert(channel, tx);
         }
     });
 
     join_handle.await.unwrap();
 }
 
+/// Processes a single channel's events in order.
+/// Every channel gets its own worker, so slow processing in one channel doesn't hold up the others.
+/// Workers stop after `WORKER_IDLE_TIMEOUT` without events; the next event starts a new one.
+async fn run_channel(pipeline: ChatPipeline, mut events: UnboundedReceiver<ChatEvent>) {
+    let mut moderation = ModerationLog::new();
+
+    loop {
+        let event = match tokio::time::timeout(WORKER_IDLE_TIMEOUT, events.recv()).await {
+            Ok(Some(event)) => event,
+            Ok(None) => break,
+            // Stop taking events, but finish any sent before closing
+            Err(_) => {
+                events.close();
+                continue;
+            }
+        };
+        match event {
+            ChatEvent::Message(mut msg) => {
+                if !pipeline.processors.process(&mut msg).await {
+                    continue;
+                }
+                // A moderator may have acted on the message before it reached us
+                if moderation.is_removed(&msg) {
+                    continue;
+                }
+                if let Some(history) = &pipeline.history {
+                    history.record_message(&msg);
+                }
+                pipeline.recent_messages.lock().await.push(msg.clone());
+                // Twitch's first-msg still works when chatters aren't tracked, or a channel has only just been
+                if msg.new_chatter || msg.first_message {
+                    let json = serde_json::to_string(&NewChatterEvent::new(&msg)).unwrap();
+                    fanout_to_channel(
+                        pipeline.state.clone(),
+                        pipeline.sub_manager.clone(),
+                        &msg.channel,
+                        json,
+                    )
+                    .await;
+                }
+                fanout_chat_message(pipeline.state.clone(), pipeline.sub_manager.clone(), &msg)
+                    .await;
+            }
+            ChatEvent::Instruction(obj) => {
+                moderation.record(&obj);
+                if let Some(history) = &pipeline.history {
+                    history.record_instruction(&obj);
+                }
+                pipeline
+                    .recent_messages
+                    .lock()
+                    .await
+                    .apply_instruction(&obj);
+                fanout_instruction(pipeline.state.clone(), pipeline.sub_manager.clone(), &obj)
+                    .await;
+            }
+            ChatEvent::Notice { .. } | ChatEvent::RoomState { .. } => {}
+        }
+    }
+}
+
+/// Sends a chat message to everyone subscribed to its channel, one copy per client.
+/// Clients subscribed through a feed get a copy labelled with the channel's feed label,
+/// even if they're also subscribed to the channel directly.
+pub async fn fanout_chat_message(
+    state: Arc<Mutex<WebsocketCollection>>,
+    sub_manager: Arc<Mutex<SubscriptionManager>>,
+    msg: &TwitchChatMessage,
+) {
+    let (subscribers, mut feeds) = {
+        let mgr = sub_manager.lock().await;
+        (
+            mgr.get_channel_subscribers(&msg.channel),
+            mgr.get_channel_feeds(&msg.channel),
+        )
+    };
+    // Clients in several feeds with the channel get the label of the first feed by name
+    feeds.sort_by(|a, b| a.label.feed.cmp(&b.label.feed));
+
+    // Client -> label of the copy they get
+    let mut copies: HashMap<String, Option<FeedLabel>> = subscribers
+        .into_iter()
+        .map(|client_id| (client_id, None))
+        .collect();
+    for feed in feeds {
+        for client_id in feed.clients {
+            let label = copies.entry(client_id).or_default();
+            if label.is_none() {
+                *label = Some(feed.label.clone());
+            }
+        }
+    }
+
+    // Global subscriptions go to every client, so nobody needs another copy
+    if let Some(label) = copies.remove("global") {
+        let mut msg = msg.clone();
+        msg.feed = label;
+        let json = serde_json::to_string(&msg).unwrap();
+        let global = HashSet::from(["global".to_string()]);
+        state.lock().await.mark_delivered(&global, &msg.message_id);
+        send_twitchchat_msg_to_subscribers(state, global, json).await;
+        return;
+    }
+
+    // Serialize each labelling once, for every client getting it
+    let mut labelled: HashMap<Option<String>, (Option<FeedLabel>, HashSet<String>)> =
+        HashMap::new();
+    for (client_id, label) in copies {
+        let feed = label.as_ref().map(|label| label.feed.clone());
+        labelled
+            .entry(feed)
+            .or_insert_with(|| (label, HashSet::new()))
+            .1
+            .insert(client_id);
+    }
+    for (label, clients) in labelled.into_values() {
+        let mut msg = msg.clone();
+        msg.feed = label;
+        let json = serde_json::to_string(&msg).unwrap();
+        state.lock().await.mark_delivered(&clients, &msg.message_id);
+        send_twitchchat_msg_to_subscribers(state.clone(), clients, json).await;
+    }
+}
+
+/// Sends a moderation instruction to everyone subscribed to its channel, directly or through a feed.
+/// Feed clients use the instruction's channel to only clear messages from that channel.
+pub async fn fanout_instruction(
+    state: Arc<Mutex<WebsocketCollection>>,
+    sub_manager: Arc<Mutex<SubscriptionManager>>,
+    instruction: &TwitchInstructionMessage,
+) {
+    let json = serde_json::to_string(instruction).unwrap();
+    fanout_to_channel(state, sub_manager, &instruction.channel, json).await;
+}
+
+/// Sends an event to everyone subscribed to a channel, directly or through a feed
+pub async fn fanout_to_channel(
+    state: Arc<Mutex<WebsocketCollection>>,
+    sub_manager: Arc<Mutex<SubscriptionManager>>,
+    channel: &str,
+    json: String,
+) {
+    let subscribers = {
+        let mgr = sub_manager.lock().await;
+        let mut subscribers = mgr.get_channel_subscribers(channel);
+        for feed in mgr.get_channel_feeds(channel) {
+            subscribers.extend(feed.clients);
+        }
+        subscribers
+    };
+
+    send_twitchchat_msg_to_subscribers(state, subscribers, json).await;
+}
+
 pub async fn send_twitchchat_msg_to_subscribers(
     state: Arc<Mutex<WebsocketCollection>>,
     subscribers: HashSet<String>,
@@ -185,6 +320,26 @@ impl TwitchChatClient<StaticLoginCredentials> {
             receiver: incoming_messages,
         }
     }
+
+    /// Logs in with the given credentials, or anonymously if there's no login.
+    /// The token may be given with or without the `oauth:` prefix.
+    pub fn with_credentials(login: Option<String>, token: Option<String>) -> Self {
+        let credentials = match login {
+            Some(login) => StaticLoginCredentials::new(
+                login,
+                token.map(|token| token.trim_start_matches("oauth:").to_string()),
+            ),
+            None => StaticLoginCredentials::anonymous(),
+        };
+        let (incoming_messages, client) = TwitchIRCClient::<
+            SecureTCPTransport,
+            StaticLoginCredentials,
+        >::new(ClientConfig::new_simple(credentials));
+        Self {
+            client,
+            receiver: incoming_messages,
+        }
+    }
 }
 
 impl Default for TwitchChatClient<StaticLoginCredentials> {
@@ -206,25 +361,7 @@ pub fn username_to_color(hash: &str) -> (u8, u8, u8) {
     let saturation = 0.75; // 75%
     let lightness: f64 = 0.65; // 65%
 
-    // Convert HSL to RGB
-    let c = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
-    let x = c * (1.0 - ((hue / 60.0) % 2.0 - 1.0).abs());
-    let m = lightness - c / 2.0;
-
-    let (r, g, b) = match (hue / 60.0) as u32 {
-        0 => (c, x, 0.0),
-        1 => (x, c, 0.0),
-        2 => (0.0, c, x),
-        3 => (0.0, x, c),
-        4 => (x, 0.0, c),
-        _ => (c, 0.0, x),
-    };
-
-    (
-        ((r + m) * 255.0) as u8,
-        ((g + m) * 255.0) as u8,
-        ((b + m) * 255.0) as u8,
-    )
+    color::hsl_to_rgb(hue, saturation, lightness)
 }
 
 pub fn triple_to_rgbcolor(triple: (u8, u8, u8)) -> RGBColor {
@@ -0,0 +1,68 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use super::message::{ModerationAction, TwitchChatMessage, TwitchInstructionMessage};
+
+/// How long moderation actions are remembered
+const MODERATION_MEMORY: Duration = Duration::from_secs(10 * 60);
+
+/// A moderation action, as remembered by ModerationLog
+#[derive(Debug, Clone)]
+struct ModerationRecord {
+    action: ModerationAction,
+    /// User ID for bans and timeouts, message ID for deletions
+    target: String,
+    /// When the action happened by the server's clock, in unix milliseconds
+    at: i64,
+}
+
+/// A short-lived record of recent bans, timeouts, deletions and chat clears per channel,
+/// so messages that reach the worker after a moderator already acted on them (replays, redelivery) respect them.
+/// Times are the server's on both sides, so local clock skew and replayed recordings don't matter.
+#[derive(Debug, Default)]
+pub struct ModerationLog {
+    channels: HashMap<String, VecDeque<ModerationRecord>>,
+}
+
+impl ModerationLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, instruction: &TwitchInstructionMessage) {
+        let at = instruction.timestamp_millis();
+        let records = self
+            .channels
+            .entry(instruction.channel.clone())
+            .or_default();
+
+        // Drop records nobody can be affected by anymore
+        let cutoff = at - MODERATION_MEMORY.as_millis() as i64;
+        while records.front().is_some_and(|record| record.at < cutoff) {
+            records.pop_front();
+        }
+
+        records.push_back(ModerationRecord {
+            action: instruction.action,
+            target: instruction.associated_id.clone(),
+            at,
+        });
+    }
+
+    /// Whether a moderator removed this message, or cleared chat after it was sent
+    pub fn is_removed(&self, msg: &TwitchChatMessage) -> bool {
+        let Some(records) = self.channels.get(&msg.channel) else {
+            return false;
+        };
+        let sent_at = msg.timestamp_millis();
+        records.iter().any(|record| match record.action {
+            ModerationAction::Delete => record.target == msg.message_id,
+            ModerationAction::Ban | ModerationAction::Timeout => {
+                record.target == msg.user.user_id && sent_at <= record.at
+            }
+            ModerationAction::ClearChat => sent_at <= record.at,
+        })
+    }
+}
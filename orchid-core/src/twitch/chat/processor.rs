@@ -0,0 +1,129 @@
+use std::{str::FromStr, sync::Arc};
+
+use async_trait::async_trait;
+use tokio::sync::RwLock;
+
+use super::{filter::ChatFilters, message::TwitchChatMessage};
+use crate::{err::OrchidError, twitch::emote::EmoteHandler};
+
+/// A step in chat message processing. Processors run in order for every message,
+/// and can enrich the message or veto it so it's never sent out.
+#[async_trait]
+pub trait MessageProcessor: Send + Sync {
+    /// Processes a message in place. Returns false to drop the message.
+    async fn process(&self, msg: &mut TwitchChatMessage) -> bool;
+}
+
+#[async_trait]
+impl<T: MessageProcessor + ?Sized> MessageProcessor for Arc<T> {
+    async fn process(&self, msg: &mut TwitchChatMessage) -> bool {
+        (**self).process(msg).await
+    }
+}
+
+/// The processors available to the chain, by configuration name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessorKind {
+    /// Per-channel chat filters
+    Filters,
+    /// Emote replacement
+    Emotes,
+    /// Highlight rules
+    Highlights,
+    /// Chatter tracking
+    Chatters,
+    /// Nickname color correction
+    Colors,
+    /// Mention linking. Runs last by default, as mention positions refer to the final message text.
+    Mentions,
+}
+
+impl FromStr for ProcessorKind {
+    type Err = OrchidError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "filters" => Ok(ProcessorKind::Filters),
+            "emotes" => Ok(ProcessorKind::Emotes),
+            "highlights" => Ok(ProcessorKind::Highlights),
+            "chatters" => Ok(ProcessorKind::Chatters),
+            "mentions" => Ok(ProcessorKind::Mentions),
+            "colors" => Ok(ProcessorKind::Colors),
+            _ => Err(OrchidError::ConfigError(format!(
+                "Unknown message processor: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// An ordered chain of message processors
+#[derive(Default)]
+pub struct ProcessorChain {
+    processors: Vec<Box<dyn MessageProcessor>>,
+}
+
+impl ProcessorChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, processor: impl MessageProcessor + 'static) {
+        self.processors.push(Box::new(processor));
+    }
+
+    /// Runs a message through every processor, stopping as soon as one drops it.
+    /// Returns false if the message was dropped.
+    pub async fn process(&self, msg: &mut TwitchChatMessage) -> bool {
+        for processor in &self.processors {
+            if !processor.process(msg).await {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Runs messages through their channel's chat filters
+pub struct FilterProcessor {
+    filters: Arc<RwLock<ChatFilters>>,
+}
+
+impl FilterProcessor {
+    pub fn new(filters: Arc<RwLock<ChatFilters>>) -> Self {
+        Self { filters }
+    }
+}
+
+#[async_trait]
+impl MessageProcessor for FilterProcessor {
+    async fn process(&self, msg: &mut TwitchChatMessage) -> bool {
+        self.filters.read().await.apply(msg)
+    }
+}
+
+/// Replaces emote names with emote markup.
+/// Channel emotes are looked up for the channel the message originated in.
+pub struct EmoteProcessor {
+    emotes: Arc<EmoteHandler>,
+}
+
+impl EmoteProcessor {
+    pub fn new(emotes: Arc<EmoteHandler>) -> Self {
+        Self { emotes }
+    }
+}
+
+#[async_trait]
+impl MessageProcessor for EmoteProcessor {
+    async fn process(&self, msg: &mut TwitchChatMessage) -> bool {
+        let scope = msg.emote_scope();
+        let (message, emotes) = self
+            .emotes
+            .process_message_with_emotes(&msg.message, &scope)
+            .await;
+        msg.message = message;
+        msg.emotes = emotes;
+        true
+    }
+}
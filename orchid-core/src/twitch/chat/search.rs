@@ -0,0 +1,193 @@
+use std::{
+    collections::HashSet,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use turbosql::select;
+
+use crate::{
+    db::ChatLogMessage,
+    err::{OrchidError, OrchidResult},
+    twitch::emote::emote_markup_to_names,
+};
+
+const DEFAULT_RESULTS: i64 = 50;
+const MAX_RESULTS: i64 = 500;
+
+/// Full-text search over stored chat messages.
+/// Turbosql can't create virtual tables, so the FTS index lives in its own database,
+/// keyed by the rowid of the message in the chat history.
+#[derive(Clone)]
+pub struct ChatSearchIndex {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl ChatSearchIndex {
+    pub fn open(path: &Path) -> OrchidResult<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE VIRTUAL TABLE IF NOT EXISTS chatsearch USING fts5(
+                text,
+                user_name,
+                display_name,
+                channel UNINDEXED,
+                message_id UNINDEXED,
+                timestamp UNINDEXED,
+                tokenize = 'unicode61 remove_diacritics 2'
+            );",
+        )?;
+        info!("Opened chat search index at {}", path.display());
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Indexes a stored message. Emotes are indexed by name.
+    pub fn index(&self, rowid: i64, msg: &ChatLogMessage) -> OrchidResult<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO chatsearch (rowid, text, user_name, display_name, channel, message_id, timestamp)
+             VALUES (?, ?, ?, ?, ?, ?, ?)",
+            params![
+                rowid,
+                emote_markup_to_names(&msg.message.message),
+                msg.user_name,
+                msg.message.user.display_name,
+                msg.channel,
+                msg.message_id,
+                msg.timestamp,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Drops messages sent before the cutoff (unix milliseconds)
+    pub fn prune_before(&self, cutoff: i64) -> OrchidResult<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM chatsearch WHERE timestamp < ?", [cutoff])?;
+        Ok(())
+    }
+
+    /// Drops all but the newest `max_rows` messages
+    pub fn prune_to(&self, max_rows: i64) -> OrchidResult<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "DELETE FROM chatsearch WHERE rowid <= (SELECT rowid FROM chatsearch ORDER BY rowid DESC LIMIT 1 OFFSET ?)",
+            [max_rows],
+        )?;
+        Ok(())
+    }
+
+    /// Searches message text and usernames, best matches first.
+    /// Supports the FTS5 query syntax, e.g. `"exact phrase"`, `user_name:someone`, `pog*`.
+    /// Messages moderators removed stay indexed, but are skipped unless asked for.
+    pub fn search(&self, query: &SearchQuery) -> OrchidResult<Vec<SearchResult>> {
+        let limit = query.limit.unwrap_or(DEFAULT_RESULTS).clamp(1, MAX_RESULTS);
+        let channel = query.channel.as_ref().map(|c| c.to_lowercase());
+
+        let mut results = vec![];
+        let mut offset = 0;
+        loop {
+            let page = self.search_page(&query.q, channel.as_deref(), limit, offset)?;
+            let exhausted = (page.len() as i64) < limit;
+            offset += limit;
+
+            let removed = if query.include_deleted {
+                HashSet::new()
+            } else {
+                removed_rowids(&page)?
+            };
+            results.extend(
+                page.into_iter()
+                    .filter(|result| !removed.contains(&result.rowid)),
+            );
+            if exhausted || results.len() as i64 >= limit {
+                break;
+            }
+        }
+        results.truncate(limit as usize);
+        Ok(results)
+    }
+
+    fn search_page(
+        &self,
+        q: &str,
+        channel: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> OrchidResult<Vec<SearchResult>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT rowid, channel, user_name, display_name, message_id, timestamp,
+                    snippet(chatsearch, 0, '<mark>', '</mark>', '…', 24)
+             FROM chatsearch
+             WHERE chatsearch MATCH ? AND (? IS NULL OR channel = ?)
+             ORDER BY rank
+             LIMIT ? OFFSET ?",
+        )?;
+        let rows = stmt
+            .query_map(params![q, channel, channel, limit, offset], |row| {
+                Ok(SearchResult {
+                    rowid: row.get(0)?,
+                    channel: row.get(1)?,
+                    user_name: row.get(2)?,
+                    display_name: row.get(3)?,
+                    message_id: row.get(4)?,
+                    timestamp: row.get(5)?,
+                    snippet: row.get(6)?,
+                })
+            })
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>());
+
+        // Most failures here come from malformed FTS queries (unbalanced quotes etc.)
+        rows.map_err(|e| OrchidError::InvalidQuery(e.to_string()))
+    }
+}
+
+/// Which of the results moderators removed from the chat history
+fn removed_rowids(results: &[SearchResult]) -> OrchidResult<HashSet<i64>> {
+    if results.is_empty() {
+        return Ok(HashSet::new());
+    }
+    // Bound as one JSON array, as the number of results varies
+    let rowids = serde_json::to_string(
+        &results
+            .iter()
+            .map(|result| result.rowid)
+            .collect::<Vec<_>>(),
+    )
+    .unwrap();
+    let removed = select!(Vec<i64> "rowid FROM chatlogmessage WHERE deleted AND rowid IN (SELECT value FROM json_each(?))", rowids)?;
+    Ok(removed.into_iter().collect())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    /// FTS5 query
+    pub q: String,
+    /// Only search this channel
+    pub channel: Option<String>,
+    pub limit: Option<i64>,
+    /// Include messages removed by moderators. Defaults to false.
+    #[serde(default)]
+    pub include_deleted: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchResult {
+    /// Rowid of the message in the chat history, usable as a history cursor for context
+    pub rowid: i64,
+    pub channel: String,
+    pub user_name: String,
+    pub display_name: String,
+    pub message_id: String,
+    /// When the message was sent, in unix milliseconds
+    pub timestamp: i64,
+    /// Message text around the match, with matches wrapped in `<mark>` tags
+    pub snippet: String,
+}
@@ -0,0 +1,319 @@
+use std::{collections::HashSet, time::Duration};
+
+use async_trait::async_trait;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+};
+use tracing::{debug, error, info, warn};
+use twitch_irc::message::{IRCMessage, IRCPrefix};
+use uuid::Uuid;
+
+use super::{replay::Recorder, ChatEvent, ChatSource, ChatSourceHandle};
+use crate::{
+    err::{OrchidError, OrchidResult},
+    twitch::chat::{
+        color::parse_hex_color,
+        message::{SharedChatOrigin, TwitchChatMessage, TwitchChatUser},
+        username_to_color,
+    },
+};
+
+/// Twitch's own IRC endpoint
+pub const TWITCH_IRC_HOST: &str = "irc.chat.twitch.tv";
+pub const TWITCH_IRC_TLS_PORT: u16 = 6697;
+pub const TWITCH_IRC_PORT: u16 = 6667;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Where to connect for IRC chat, and who to log in as
+#[derive(Debug, Clone)]
+pub struct IrcEndpoint {
+    pub host: String,
+    pub port: u16,
+    pub tls: bool,
+    /// Login name. Anonymous (`justinfan`) if unset.
+    pub login: Option<String>,
+    /// Password or OAuth token. Tokens for Twitch may be given with or without the `oauth:` prefix.
+    pub token: Option<String>,
+}
+
+impl IrcEndpoint {
+    /// Whether this is Twitch's TLS endpoint, which the twitch-irc client connects to
+    pub fn is_twitch(&self) -> bool {
+        self.host == TWITCH_IRC_HOST && self.tls && self.port == TWITCH_IRC_TLS_PORT
+    }
+
+    /// The password to log in with. Twitch wants OAuth tokens prefixed with `oauth:`,
+    /// other servers get the password as given.
+    fn password(&self) -> Option<String> {
+        let token = self.token.as_deref()?.trim();
+        if self.host == TWITCH_IRC_HOST {
+            Some(format!("oauth:{}", token.trim_start_matches("oauth:")))
+        } else {
+            Some(token.to_string())
+        }
+    }
+}
+
+impl Default for IrcEndpoint {
+    fn default() -> Self {
+        Self {
+            host: TWITCH_IRC_HOST.to_string(),
+            port: TWITCH_IRC_TLS_PORT,
+            tls: true,
+            login: None,
+            token: None,
+        }
+    }
+}
+
+/// Chat from any IRC server, such as a local or Twitch-compatible test server.
+/// Twitch tags are parsed when the server sends them, and plain IRC messages are accepted otherwise.
+pub struct IrcChatSource {
+    endpoint: IrcEndpoint,
+    recorder: Option<Recorder>,
+}
+
+impl IrcChatSource {
+    pub fn new(endpoint: IrcEndpoint) -> Self {
+        Self {
+            endpoint,
+            recorder: None,
+        }
+    }
+
+    /// Records every raw line received, for replaying later
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+}
+
+enum IrcCommand {
+    Join(String),
+    Part(String),
+}
+
+struct IrcChatHandle {
+    commands: UnboundedSender<IrcCommand>,
+}
+
+impl ChatSourceHandle for IrcChatHandle {
+    fn join(&self, channel: String) -> OrchidResult<()> {
+        self.commands
+            .send(IrcCommand::Join(channel))
+            .map_err(|_| OrchidError::ConnectionError("IRC connection closed".to_string()))
+    }
+
+    fn part(&self, channel: String) {
+        let _ = self.commands.send(IrcCommand::Part(channel));
+    }
+}
+
+#[async_trait]
+impl ChatSource for IrcChatSource {
+    async fn start(self: Box<Self>) -> (Box<dyn ChatSourceHandle>, UnboundedReceiver<ChatEvent>) {
+        let (commands, command_rx) = mpsc::unbounded_channel();
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(run(*self, command_rx, tx));
+
+        (Box::new(IrcChatHandle { commands }), rx)
+    }
+}
+
+trait IrcStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> IrcStream for T {}
+
+/// Keeps a connection to the server up, reconnecting and rejoining channels when it drops.
+async fn run(
+    mut source: IrcChatSource,
+    mut commands: UnboundedReceiver<IrcCommand>,
+    tx: UnboundedSender<ChatEvent>,
+) {
+    let mut channels = HashSet::new();
+
+    loop {
+        match connect(&source.endpoint).await {
+            Ok(stream) => {
+                info!(
+                    "Connected to IRC server {}:{}",
+                    source.endpoint.host, source.endpoint.port
+                );
+                match session(stream, &mut source, &mut channels, &mut commands, &tx).await {
+                    Ok(()) => return,
+                    Err(e) => error!("IRC connection lost: {}", e),
+                }
+            }
+            Err(e) => error!("Failed to connect to IRC server: {}", e),
+        }
+
+        if tx.is_closed() {
+            return;
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn connect(endpoint: &IrcEndpoint) -> OrchidResult<Box<dyn IrcStream>> {
+    let tcp = TcpStream::connect((endpoint.host.as_str(), endpoint.port)).await?;
+    if !endpoint.tls {
+        return Ok(Box::new(tcp));
+    }
+
+    let connector =
+        native_tls::TlsConnector::new().map_err(|e| OrchidError::ConnectionError(e.to_string()))?;
+    let stream = tokio_native_tls::TlsConnector::from(connector)
+        .connect(&endpoint.host, tcp)
+        .await
+        .map_err(|e| OrchidError::ConnectionError(e.to_string()))?;
+    Ok(Box::new(stream))
+}
+
+/// Runs a single connection. Returns `Ok` once nobody is listening anymore, and `Err` if the connection drops.
+async fn session(
+    stream: Box<dyn IrcStream>,
+    source: &mut IrcChatSource,
+    channels: &mut HashSet<String>,
+    commands: &mut UnboundedReceiver<IrcCommand>,
+    tx: &UnboundedSender<ChatEvent>,
+) -> OrchidResult<()> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+
+    // Log in. Servers that don't know about Twitch capabilities will just reject the request.
+    // Channels are joined once the server welcomes us, as servers reject joins before that.
+    let login = source
+        .endpoint
+        .login
+        .clone()
+        .unwrap_or_else(|| format!("justinfan{}", rand::random::<u16>() % 10000 + 10000));
+    let mut handshake = String::from("CAP REQ :twitch.tv/tags twitch.tv/commands\r\n");
+    if let Some(password) = source.endpoint.password() {
+        handshake.push_str(&format!("PASS {}\r\n", password));
+    }
+    handshake.push_str(&format!(
+        "NICK {}\r\nUSER {} 0 * :{}\r\n",
+        login, login, login
+    ));
+    writer.write_all(handshake.as_bytes()).await?;
+    let mut registered = false;
+
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let Some(line) = line? else {
+                    return Err(OrchidError::ConnectionError("Server closed the connection".to_string()));
+                };
+                if let Some(recorder) = &source.recorder {
+                    if let Err(e) = recorder.record_raw(line.clone()) {
+                        error!("Failed to record message: {}", e);
+                    }
+                }
+
+                let irc = match IRCMessage::parse(&line) {
+                    Ok(irc) => irc,
+                    Err(e) => {
+                        warn!("Unparseable IRC line {:?}: {}", line, e);
+                        continue;
+                    }
+                };
+                match irc.command.as_str() {
+                    "PING" => {
+                        let reply = format!("PONG :{}\r\n", irc.params.join(" "));
+                        writer.write_all(reply.as_bytes()).await?;
+                        continue;
+                    }
+                    // Whether or not the capabilities were granted, registration can go on
+                    "CAP" if irc.params.get(1).is_some_and(|sub| sub == "ACK" || sub == "NAK") => {
+                        writer.write_all(b"CAP END\r\n").await?;
+                        continue;
+                    }
+                    // RPL_WELCOME, we're logged in
+                    "001" => {
+                        registered = true;
+                        let joins: String = channels
+                            .iter()
+                            .map(|channel| format!("JOIN #{}\r\n", channel))
+                            .collect();
+                        writer.write_all(joins.as_bytes()).await?;
+                        continue;
+                    }
+                    _ => {}
+                }
+
+                if let Some(event) = ChatEvent::from_irc_message(irc) {
+                    if tx.send(event).is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+            command = commands.recv() => {
+                let line = match command {
+                    Some(IrcCommand::Join(channel)) => {
+                        let line = format!("JOIN #{}\r\n", channel);
+                        channels.insert(channel);
+                        // Joined on welcome otherwise
+                        if !registered {
+                            continue;
+                        }
+                        line
+                    }
+                    Some(IrcCommand::Part(channel)) => {
+                        let line = format!("PART #{}\r\n", channel);
+                        channels.remove(&channel);
+                        if !registered {
+                            continue;
+                        }
+                        line
+                    }
+                    None => return Ok(()),
+                };
+                debug!("IRC > {}", line.trim_end());
+                writer.write_all(line.as_bytes()).await?;
+            }
+        }
+    }
+}
+
+/// Builds a chat message from a PRIVMSG that didn't parse as a Twitch message,
+/// using whichever Twitch tags are present and falling back to the IRC prefix otherwise.
+pub fn plain_privmsg(irc: &IRCMessage) -> Option<TwitchChatMessage> {
+    if irc.command != "PRIVMSG" {
+        return None;
+    }
+    let channel = irc.params.first()?.trim_start_matches('#').to_string();
+    let text = irc.params.get(1)?.clone();
+    let nick = match irc.prefix.as_ref()? {
+        IRCPrefix::Full { nick, .. } => nick.clone(),
+        IRCPrefix::HostOnly { host } => host.clone(),
+    };
+
+    let tag = |key: &str| irc.tags.0.get(key).filter(|v| !v.is_empty()).cloned();
+    let user_name = nick.to_lowercase();
+    let channel_id = tag("room-id").unwrap_or_else(|| channel.clone());
+    let original_color = tag("color").and_then(|color| parse_hex_color(&color));
+    let nickname_color = original_color.unwrap_or_else(|| username_to_color(&user_name));
+
+    Some(TwitchChatMessage {
+        msg_type: "PRIVMSG".to_string(),
+        shared_chat: SharedChatOrigin::from_tags(&irc.tags, &channel_id),
+        channel,
+        channel_id,
+        user: TwitchChatUser {
+            user_id: tag("user-id").unwrap_or_else(|| user_name.clone()),
+            display_name: tag("display-name").unwrap_or(nick),
+            user_name,
+        },
+        nickname_color,
+        original_color,
+        message: text,
+        message_id: tag("id").unwrap_or_else(|| Uuid::new_v4().to_string()),
+        server_timestamp: chrono::Utc::now().to_string(),
+        first_message: tag("first-msg").is_some_and(|v| v == "1"),
+        returning_chatter: tag("returning-chatter").is_some_and(|v| v == "1"),
+        ..Default::default()
+    })
+}
@@ -0,0 +1,217 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::Deserialize;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use uuid::Uuid;
+
+use super::{ChatEvent, ChatSource, ChatSourceHandle};
+use crate::{
+    err::{OrchidError, OrchidResult},
+    twitch::chat::{
+        message::{ModerationAction, TwitchChatMessage, TwitchChatUser, TwitchInstructionMessage},
+        username_to_color,
+    },
+};
+
+/// Emote names sprinkled into mock messages. These are FrankerFaceZ global emotes.
+const MOCK_EMOTES: &[&str] = &["ZreknarF", "LilZ", "ZliL", "CatBag", "BeanieHipster"];
+
+/// How many recently sent messages are kept around to delete, or to ban the author of
+const RECENT_MESSAGES: usize = 50;
+
+/// A line of mock chat. Same shape as the web client's `exampleMsgs.ts`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MockLine {
+    pub message: String,
+    pub user: String,
+}
+
+/// Loads a mock chat corpus from a JSON file containing an array of `{ "message", "user" }` objects.
+pub fn load_corpus(path: &Path) -> OrchidResult<Vec<MockLine>> {
+    let data = std::fs::read_to_string(path)?;
+    let corpus: Vec<MockLine> = serde_json::from_str(&data)
+        .map_err(|e| OrchidError::ConfigError(format!("Invalid mock corpus: {}", e)))?;
+    if corpus.is_empty() {
+        return Err(OrchidError::ConfigError("Mock corpus is empty".to_string()));
+    }
+    Ok(corpus)
+}
+
+/// A small built-in corpus, taken from `exampleMsgs.ts`
+pub fn default_corpus() -> Vec<MockLine> {
+    [
+        ("ash_k", "Pikachu, use Thunderbolt!"),
+        ("brock_b", "I need more potions before the next battle."),
+        ("misty_w", "Look at all the Magikarp flopping around!"),
+        ("ash_k", "I just caught a rare Gyarados!"),
+        ("brock_b", "You should try using a Great Ball for that one."),
+        ("misty_w", "We’re almost to the Pokémon Center!"),
+        ("ash_k", "Got a badge from the Pewter Gym!"),
+        ("brock_b", "Time to teach my Pokémon some new moves."),
+        ("misty_w", "My Staryu is so sparkly!"),
+        ("ash_k", "Is that a shiny Pokémon?!"),
+        ("narrator", "Team Rocket's blasting off again!"),
+        ("lillie", "Nebby, get back in the bag!"),
+        ("hau", "Did someone say malasadas?"),
+        ("gladion", "Silvally, use Multi-Attack!"),
+        ("guzma", "Wicke! More potions, please!"),
+        ("maxie", "Groudon, awaken!"),
+        ("archie", "Kyogre, arise!"),
+        ("may", "Contests are so much fun!"),
+        ("wally", "Steven... is so cool."),
+        ("n", "Pokémon are not tools to be used!"),
+    ]
+    .into_iter()
+    .map(|(user, message)| MockLine {
+        message: message.to_string(),
+        user: user.to_string(),
+    })
+    .collect()
+}
+
+/// Offline chat source, generating messages from a corpus on every joined channel.
+/// Occasionally deletes a recent message or bans a user, to exercise moderation handling.
+pub struct MockChatSource {
+    /// Messages per second, per channel
+    rate: f64,
+    corpus: Vec<MockLine>,
+}
+
+impl MockChatSource {
+    pub fn new(rate: f64, corpus: Vec<MockLine>) -> Self {
+        Self { rate, corpus }
+    }
+}
+
+struct MockChatHandle {
+    channels: Arc<Mutex<HashSet<String>>>,
+}
+
+impl ChatSourceHandle for MockChatHandle {
+    fn join(&self, channel: String) -> OrchidResult<()> {
+        self.channels.lock().unwrap().insert(channel);
+        Ok(())
+    }
+
+    fn part(&self, channel: String) {
+        self.channels.lock().unwrap().remove(&channel);
+    }
+}
+
+#[async_trait]
+impl ChatSource for MockChatSource {
+    async fn start(self: Box<Self>) -> (Box<dyn ChatSourceHandle>, UnboundedReceiver<ChatEvent>) {
+        let channels = Arc::new(Mutex::new(HashSet::new()));
+        let (tx, rx) = mpsc::unbounded_channel();
+        let interval = Duration::from_secs_f64(1.0 / self.rate.max(0.01))
+            // Intervals can't be zero
+            .max(Duration::from_millis(1));
+
+        let joined = channels.clone();
+        tokio::spawn(async move {
+            let mut rng = StdRng::from_entropy();
+            // Recently sent (channel, message id, author), for deletions and bans
+            let mut recent: VecDeque<(String, String, TwitchChatUser)> = VecDeque::new();
+            // (channel, user) pairs that have chatted, to flag first messages
+            let mut chatted: HashSet<(String, String)> = HashSet::new();
+            let mut ticker = tokio::time::interval(interval);
+
+            loop {
+                ticker.tick().await;
+                let channels: Vec<String> = joined.lock().unwrap().iter().cloned().collect();
+
+                for channel in channels {
+                    let roll: f64 = rng.gen();
+                    let candidates: Vec<_> =
+                        recent.iter().filter(|(c, _, _)| *c == channel).collect();
+                    let target = candidates
+                        .choose(&mut rng)
+                        .map(|(_, message_id, user)| (message_id.clone(), user.clone()));
+
+                    let event = match (roll, target) {
+                        // Delete a recent message
+                        (r, Some((message_id, user))) if r < 0.05 => {
+                            ChatEvent::Instruction(TwitchInstructionMessage {
+                                msg_type: "CLEARMSG".to_string(),
+                                channel: channel.clone(),
+                                msg_subtype: "SINGLE".to_string(),
+                                associated_id: message_id,
+                                action: ModerationAction::Delete,
+                                target_login: Some(user.user_name),
+                                target_user_id: Some(user.user_id),
+                                timeout_duration: None,
+                                server_timestamp: chrono::Utc::now().to_string(),
+                            })
+                        }
+                        // Ban the author of a recent message
+                        (r, Some((_, user))) if r < 0.07 => {
+                            ChatEvent::Instruction(TwitchInstructionMessage {
+                                msg_type: "CLEARCHAT".to_string(),
+                                channel: channel.clone(),
+                                msg_subtype: "REMOVE_USER_MESSAGES".to_string(),
+                                associated_id: user.user_id.clone(),
+                                action: ModerationAction::Ban,
+                                target_login: Some(user.user_name),
+                                target_user_id: Some(user.user_id),
+                                timeout_duration: None,
+                                server_timestamp: chrono::Utc::now().to_string(),
+                            })
+                        }
+                        _ => {
+                            let line = self.corpus.choose(&mut rng).unwrap();
+                            let mut text = line.message.clone();
+                            if rng.gen_bool(0.3) {
+                                text.push(' ');
+                                text.push_str(MOCK_EMOTES.choose(&mut rng).unwrap());
+                            }
+                            let mut msg = mock_message(&channel, line, text);
+                            msg.first_message =
+                                chatted.insert((channel.clone(), line.user.clone()));
+
+                            recent.push_back((
+                                channel.clone(),
+                                msg.message_id.clone(),
+                                msg.user.clone(),
+                            ));
+                            if recent.len() > RECENT_MESSAGES {
+                                recent.pop_front();
+                            }
+                            ChatEvent::Message(msg)
+                        }
+                    };
+
+                    if tx.send(event).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        (Box::new(MockChatHandle { channels }), rx)
+    }
+}
+
+fn mock_message(channel: &str, line: &MockLine, text: String) -> TwitchChatMessage {
+    TwitchChatMessage {
+        msg_type: "PRIVMSG".to_string(),
+        channel: channel.to_string(),
+        channel_id: format!("mock-{}", channel),
+        user: TwitchChatUser {
+            user_id: format!("mock-{}", line.user),
+            user_name: line.user.clone(),
+            display_name: line.user.clone(),
+        },
+        nickname_color: username_to_color(&line.user),
+        message: text,
+        message_id: Uuid::new_v4().to_string(),
+        server_timestamp: chrono::Utc::now().to_string(),
+        ..Default::default()
+    }
+}
@@ -0,0 +1,190 @@
+use async_trait::async_trait;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tracing::error;
+use twitch_irc::message::{ClearChatAction, IRCMessage, ServerMessage};
+
+use super::{
+    message::{ModerationAction, TwitchChatMessage, TwitchInstructionMessage},
+    TwitchChatClient,
+};
+use crate::{
+    config::{ChatSourceKind, Config},
+    err::{OrchidError, OrchidResult},
+};
+
+pub mod irc;
+pub mod mock;
+pub mod replay;
+pub mod twitch;
+
+/// A normalized chat event, independent of where the chat came from
+#[derive(Debug, Clone)]
+pub enum ChatEvent {
+    /// A chat message, not yet postprocessed
+    Message(TwitchChatMessage),
+    /// A moderation instruction (CLEARCHAT/CLEARMSG)
+    Instruction(TwitchInstructionMessage),
+    /// A notice from the server, for logging
+    Notice {
+        channel: Option<String>,
+        message: String,
+    },
+    /// A channel's state, sent when it's joined. Tells us the channel's ID.
+    RoomState { channel: String, channel_id: String },
+}
+
+impl ChatEvent {
+    /// Normalizes a raw Twitch IRC message. Returns `None` for messages we don't handle.
+    pub fn from_server_message(message: ServerMessage) -> Option<Self> {
+        match message {
+            ServerMessage::Privmsg(msg) => match TwitchChatMessage::try_from(msg) {
+                Ok(msg) => Some(ChatEvent::Message(msg)),
+                Err(e) => {
+                    error!("Error converting message to TwitchChatMessage: {:?}", e);
+                    None
+                }
+            },
+            ServerMessage::ClearChat(msg) => {
+                let channel = msg.channel_login.to_string();
+                let server_timestamp = msg.server_timestamp.to_string();
+
+                // Parse message into own format
+                let obj = match msg.action {
+                    // Request to clear chat
+                    ClearChatAction::ChatCleared => TwitchInstructionMessage {
+                        msg_type: "CLEARCHAT".to_string(),
+                        channel,
+                        msg_subtype: "CLEAR_CHAT".to_string(),
+                        associated_id: "".to_string(),
+                        action: ModerationAction::ClearChat,
+                        target_login: None,
+                        target_user_id: None,
+                        timeout_duration: None,
+                        server_timestamp,
+                    },
+                    // The below two are when a user is removed from chat.
+                    // I think the Twitch API just sends their messages once they are unbanned.
+                    // Should be fine.
+                    ClearChatAction::UserBanned {
+                        user_login,
+                        user_id,
+                    } => TwitchInstructionMessage {
+                        msg_type: "CLEARCHAT".to_string(),
+                        channel,
+                        msg_subtype: "REMOVE_USER_MESSAGES".to_string(),
+                        associated_id: user_id.clone(),
+                        action: ModerationAction::Ban,
+                        target_login: Some(user_login),
+                        target_user_id: Some(user_id),
+                        timeout_duration: None,
+                        server_timestamp,
+                    },
+                    ClearChatAction::UserTimedOut {
+                        user_login,
+                        user_id,
+                        timeout_length,
+                    } => TwitchInstructionMessage {
+                        msg_type: "CLEARCHAT".to_string(),
+                        channel,
+                        msg_subtype: "REMOVE_USER_MESSAGES".to_string(),
+                        associated_id: user_id.clone(),
+                        action: ModerationAction::Timeout,
+                        target_login: Some(user_login),
+                        target_user_id: Some(user_id),
+                        timeout_duration: Some(timeout_length.as_secs()),
+                        server_timestamp,
+                    },
+                };
+                Some(ChatEvent::Instruction(obj))
+            }
+            ServerMessage::ClearMsg(msg) => {
+                Some(ChatEvent::Instruction(TwitchInstructionMessage {
+                    msg_type: "CLEARMSG".to_string(),
+                    channel: msg.channel_login.to_string(),
+                    msg_subtype: "SINGLE".to_string(),
+                    associated_id: msg.message_id.to_string(),
+                    action: ModerationAction::Delete,
+                    target_login: Some(msg.sender_login),
+                    target_user_id: None,
+                    timeout_duration: None,
+                    server_timestamp: msg.server_timestamp.to_string(),
+                }))
+            }
+            ServerMessage::Notice(msg) => Some(ChatEvent::Notice {
+                channel: msg.channel_login,
+                message: msg.message_text,
+            }),
+            ServerMessage::RoomState(msg) => Some(ChatEvent::RoomState {
+                channel: msg.channel_login,
+                channel_id: msg.channel_id,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Normalizes a parsed IRC line. Lines that aren't valid Twitch messages,
+    /// such as messages from a plain IRC server, are accepted if they're a PRIVMSG.
+    pub fn from_irc_message(irc: IRCMessage) -> Option<Self> {
+        match ServerMessage::try_from(irc.clone()) {
+            Ok(message) => Self::from_server_message(message),
+            Err(_) => irc::plain_privmsg(&irc).map(ChatEvent::Message),
+        }
+    }
+}
+
+/// Somewhere chat comes from, such as Twitch IRC.
+#[async_trait]
+pub trait ChatSource: Send {
+    /// Starts the source, consuming it.
+    /// Returns a handle for joining and leaving channels, and the stream of normalized events.
+    async fn start(self: Box<Self>) -> (Box<dyn ChatSourceHandle>, UnboundedReceiver<ChatEvent>);
+}
+
+/// Joins and leaves channels on a running ChatSource
+pub trait ChatSourceHandle: Send + Sync {
+    fn join(&self, channel: String) -> OrchidResult<()>;
+    fn part(&self, channel: String);
+}
+
+/// Creates the chat source selected in the config
+pub fn from_config(config: &Config) -> OrchidResult<Box<dyn ChatSource>> {
+    Ok(match config.chat_source {
+        ChatSourceKind::Twitch => {
+            let recorder = match &config.record_path {
+                Some(path) => Some(replay::Recorder::create(path)?),
+                None => None,
+            };
+            let endpoint = &config.irc;
+            if endpoint.is_twitch() {
+                let client = TwitchChatClient::with_credentials(
+                    endpoint.login.clone(),
+                    endpoint.token.clone(),
+                );
+                let source = twitch::TwitchChatSource::with_client(client);
+                match recorder {
+                    Some(recorder) => Box::new(source.with_recorder(recorder)),
+                    None => Box::new(source),
+                }
+            } else {
+                let source = irc::IrcChatSource::new(endpoint.clone());
+                match recorder {
+                    Some(recorder) => Box::new(source.with_recorder(recorder)),
+                    None => Box::new(source),
+                }
+            }
+        }
+        ChatSourceKind::Replay => {
+            let path = config.replay_path.clone().ok_or_else(|| {
+                OrchidError::ConfigError("ORCHID_REPLAY_PATH is required for replay".to_string())
+            })?;
+            Box::new(replay::ReplayChatSource::new(path, config.replay_speed))
+        }
+        ChatSourceKind::Mock => {
+            let corpus = match &config.mock_corpus {
+                Some(path) => mock::load_corpus(path)?,
+                None => mock::default_corpus(),
+            };
+            Box::new(mock::MockChatSource::new(config.mock_rate, corpus))
+        }
+    })
+}
@@ -0,0 +1,238 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::AsyncBufReadExt,
+    sync::{
+        mpsc::{self, error::TryRecvError, UnboundedReceiver, UnboundedSender},
+        Notify,
+    },
+    time::Instant,
+};
+use tracing::{debug, error, info, warn};
+use twitch_irc::message::{AsRawIRC, IRCMessage, ServerMessage};
+
+use super::{ChatEvent, ChatSource, ChatSourceHandle};
+use crate::err::{OrchidError, OrchidResult};
+
+/// A single recorded IRC message. Recordings are stored as one of these per line (JSON lines).
+#[derive(Debug, Serialize, Deserialize)]
+struct RecordedMessage {
+    /// When the message was received, in unix milliseconds
+    timestamp: i64,
+    /// The raw IRC line
+    raw: String,
+}
+
+/// Longest a recorded line waits in the buffer while chat is busy
+const RECORDER_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Records raw IRC messages to a file, so a session can be replayed later.
+/// Lines are written on a blocking thread so they never hold up chat.
+pub struct Recorder {
+    tx: UnboundedSender<String>,
+}
+
+impl Recorder {
+    /// Creates a recorder, appending to the file if it already exists
+    pub fn create(path: &Path) -> OrchidResult<Self> {
+        let file = File::options().create(true).append(true).open(path)?;
+        info!("Recording chat to {}", path.display());
+
+        let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            let mut file = BufWriter::new(file);
+            let mut last_flush = std::time::Instant::now();
+            let mut next = rx.blocking_recv();
+            while let Some(line) = next {
+                if let Err(e) = writeln!(file, "{}", line) {
+                    error!("Failed to record to {}: {}", path.display(), e);
+                }
+                // Flush once caught up, or every so often while busy,
+                // so a crash doesn't lose the part we want to debug
+                next = match rx.try_recv() {
+                    Ok(line) => Some(line),
+                    Err(TryRecvError::Empty) => {
+                        flush(&mut file, &path, &mut last_flush);
+                        rx.blocking_recv()
+                    }
+                    Err(TryRecvError::Disconnected) => None,
+                };
+                if last_flush.elapsed() >= RECORDER_FLUSH_INTERVAL {
+                    flush(&mut file, &path, &mut last_flush);
+                }
+            }
+            flush(&mut file, &path, &mut last_flush);
+        });
+
+        Ok(Self { tx })
+    }
+
+    pub fn record(&self, message: &ServerMessage) -> OrchidResult<()> {
+        self.record_raw(message.source().as_raw_irc())
+    }
+
+    /// Records a raw IRC line, for servers whose messages don't parse as Twitch messages
+    pub fn record_raw(&self, raw: String) -> OrchidResult<()> {
+        let line = RecordedMessage {
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            raw,
+        };
+        let json = serde_json::to_string(&line).map_err(|e| OrchidError::Unknown(e.to_string()))?;
+        self.tx
+            .send(json)
+            .map_err(|_| OrchidError::Unknown("The recorder has stopped".to_string()))
+    }
+}
+
+fn flush(file: &mut BufWriter<File>, path: &Path, last_flush: &mut std::time::Instant) {
+    if let Err(e) = file.flush() {
+        error!("Failed to flush recording {}: {}", path.display(), e);
+    }
+    *last_flush = std::time::Instant::now();
+}
+
+/// How fast to replay a recording
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplaySpeed {
+    /// Same timing as the recording
+    Original,
+    /// Timing scaled by a factor, e.g. 2.0 for double speed
+    Scaled(f64),
+    /// As fast as possible
+    Max,
+}
+
+impl FromStr for ReplaySpeed {
+    type Err = OrchidError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "original" => Ok(ReplaySpeed::Original),
+            "max" => Ok(ReplaySpeed::Max),
+            factor => match factor.parse::<f64>() {
+                Ok(factor) if factor > 0.0 => Ok(ReplaySpeed::Scaled(factor)),
+                _ => Err(OrchidError::ConfigError(format!(
+                    "Invalid replay speed: {}",
+                    s
+                ))),
+            },
+        }
+    }
+}
+
+/// Replays a recorded IRC session, as if it were live.
+/// Messages go through the same normalization as live chat.
+/// Playback starts once the first channel is joined, so overlays don't miss the start.
+pub struct ReplayChatSource {
+    path: PathBuf,
+    speed: ReplaySpeed,
+}
+
+impl ReplayChatSource {
+    pub fn new(path: PathBuf, speed: ReplaySpeed) -> Self {
+        Self { path, speed }
+    }
+}
+
+struct ReplayChatHandle {
+    started: Arc<Notify>,
+}
+
+impl ChatSourceHandle for ReplayChatHandle {
+    fn join(&self, channel: String) -> OrchidResult<()> {
+        debug!("Replay: joined {}", channel);
+        self.started.notify_one();
+        Ok(())
+    }
+
+    fn part(&self, channel: String) {
+        debug!("Replay: parted {}", channel);
+    }
+}
+
+#[async_trait]
+impl ChatSource for ReplayChatSource {
+    async fn start(self: Box<Self>) -> (Box<dyn ChatSourceHandle>, UnboundedReceiver<ChatEvent>) {
+        let started = Arc::new(Notify::new());
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let notified = started.clone();
+        tokio::spawn(async move {
+            let file = match tokio::fs::File::open(&self.path).await {
+                Ok(file) => file,
+                Err(e) => {
+                    error!("Failed to open recording {}: {}", self.path.display(), e);
+                    return;
+                }
+            };
+
+            notified.notified().await;
+            info!(
+                "Replaying {} at {:?} speed",
+                self.path.display(),
+                self.speed
+            );
+
+            let start = Instant::now();
+            let mut first_timestamp = None;
+
+            let mut lines = tokio::io::BufReader::new(file).lines();
+            loop {
+                let line = match lines.next_line().await {
+                    Ok(Some(line)) if !line.trim().is_empty() => line,
+                    Ok(Some(_)) => continue,
+                    Ok(None) => break,
+                    Err(e) => {
+                        error!("Failed to read recording: {}", e);
+                        break;
+                    }
+                };
+                let recorded: RecordedMessage = match serde_json::from_str(&line) {
+                    Ok(recorded) => recorded,
+                    Err(e) => {
+                        warn!("Skipping malformed recording line: {}", e);
+                        continue;
+                    }
+                };
+
+                // Wait until the message is due, relative to the first message
+                let offset =
+                    recorded.timestamp - *first_timestamp.get_or_insert(recorded.timestamp);
+                let due = match self.speed {
+                    ReplaySpeed::Original => Some(offset as f64),
+                    ReplaySpeed::Scaled(factor) => Some(offset as f64 / factor),
+                    ReplaySpeed::Max => None,
+                };
+                if let Some(due) = due {
+                    let due = Duration::from_millis(due.max(0.0) as u64);
+                    tokio::time::sleep_until(start + due).await;
+                }
+
+                match IRCMessage::parse(&recorded.raw) {
+                    Ok(irc) => {
+                        if let Some(event) = ChatEvent::from_irc_message(irc) {
+                            if tx.send(event).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Err(e) => warn!("Skipping unparseable recorded message: {}", e),
+                }
+            }
+
+            info!("Replay of {} finished", self.path.display());
+        });
+
+        (Box::new(ReplayChatHandle { started }), rx)
+    }
+}
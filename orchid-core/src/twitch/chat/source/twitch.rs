@@ -0,0 +1,74 @@
+use async_trait::async_trait;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tracing::error;
+use twitch_irc::{login::StaticLoginCredentials, SecureTCPTransport, TwitchIRCClient};
+
+use super::{replay::Recorder, ChatEvent, ChatSource, ChatSourceHandle};
+use crate::{err::OrchidResult, twitch::chat::TwitchChatClient};
+
+/// Chat from Twitch IRC
+pub struct TwitchChatSource {
+    client: TwitchChatClient<StaticLoginCredentials>,
+    recorder: Option<Recorder>,
+}
+
+impl TwitchChatSource {
+    pub fn new() -> Self {
+        Self::with_client(TwitchChatClient::new())
+    }
+
+    pub fn with_client(client: TwitchChatClient<StaticLoginCredentials>) -> Self {
+        Self {
+            client,
+            recorder: None,
+        }
+    }
+
+    /// Records every raw message received, for replaying later
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+}
+
+impl Default for TwitchChatSource {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl ChatSource for TwitchChatSource {
+    async fn start(self: Box<Self>) -> (Box<dyn ChatSourceHandle>, UnboundedReceiver<ChatEvent>) {
+        let TwitchChatSource { client, recorder } = *self;
+        let (client, mut receiver) = client.get_pair().await;
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            while let Some(message) = receiver.recv().await {
+                if let Some(recorder) = &recorder {
+                    if let Err(e) = recorder.record(&message) {
+                        error!("Failed to record message: {}", e);
+                    }
+                }
+                if let Some(event) = ChatEvent::from_server_message(message) {
+                    if tx.send(event).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        (Box::new(client), rx)
+    }
+}
+
+impl ChatSourceHandle for TwitchIRCClient<SecureTCPTransport, StaticLoginCredentials> {
+    fn join(&self, channel: String) -> OrchidResult<()> {
+        Ok(TwitchIRCClient::join(self, channel)?)
+    }
+
+    fn part(&self, channel: String) {
+        TwitchIRCClient::part(self, channel)
+    }
+}
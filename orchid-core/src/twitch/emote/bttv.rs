@@ -0,0 +1,379 @@
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// Gets BetterTTV emotes
+use crate::db::EmoteCacheEntry;
+use crate::twitch::emote::cache::{EmoteTtls, Fetched, TtlCache};
+use crate::twitch::emote::effects::EmoteEffect;
+use crate::twitch::emote::images::{EmoteImageFormat, EmoteImageScale, EmoteImages};
+use crate::twitch::emote::index::{
+    index_emotes, EmoteIndex, EmoteProvider, EmoteTier, ScopedEmotes,
+};
+use crate::twitch::emote::store::{read_json, CachedEmoteSet, EmoteStore, FetchedSets};
+use crate::twitch::emote::Emote;
+use crate::twitch::emote::EmoteManager;
+use crate::twitch::emote::EmoteScope;
+use crate::twitch::emote::ZERO_WIDTH_EFFECT;
+use reqwest::Client;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::sync::RwLock;
+use tracing::error;
+
+/// BetterTTV's public API
+pub const BTTV_API_URL: &str = "https://api.betterttv.net/3";
+/// Where BetterTTV serves emote images
+pub const BTTV_CDN_URL: &str = "https://cdn.betterttv.net/emote";
+
+/// Global emotes BTTV draws over the previous emote
+const ZERO_WIDTH_EMOTES: &[&str] = &[
+    "cvHazmat",
+    "cvMask",
+    "IceCold",
+    "SoSnowy",
+    "SantaHat",
+    "TopHat",
+    "ReinDeer",
+    "CandyCane",
+];
+
+type FetchResult<T> = Result<Option<T>, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Cache key for the global emotes
+const GLOBAL: &str = "@global";
+
+pub struct BttvEmoteManager {
+    /// Emote caches. Only locked while reading or updating, never during requests.
+    cache: RwLock<BttvCache>,
+    client: Client,
+    base_url: String,
+    ttls: EmoteTtls,
+    /// Where fetched emotes are kept across restarts
+    store: Option<EmoteStore>,
+}
+
+#[derive(Default)]
+struct BttvCache {
+    global: TtlCache<Arc<EmoteIndex>>,
+    /// Channel ID -> channel and shared emotes by name
+    channels: TtlCache<Arc<EmoteIndex>>,
+}
+
+impl BttvCache {
+    /// Restores a fetch from the emote store
+    fn restore(&mut self, entry: EmoteCacheEntry, ttls: &EmoteTtls) {
+        let cache = match entry.tier.parse::<EmoteTier>() {
+            Ok(EmoteTier::Global) => &mut self.global,
+            Ok(EmoteTier::Channel) => &mut self.channels,
+            _ => return,
+        };
+        let index = (!entry.missing).then(|| index_sets(&entry.sets));
+        cache.restore(&entry.scope_key, index, entry.age(), ttls);
+    }
+}
+
+/// BTTV emotes come in one set per scope
+fn index_sets(sets: &[CachedEmoteSet]) -> Arc<EmoteIndex> {
+    index_emotes(sets.iter().flat_map(|set| set.emotes.iter().cloned()))
+}
+
+impl BttvEmoteManager {
+    /// Creates a manager using the given API base URL, e.g. `BTTV_API_URL`
+    pub fn new(base_url: &str, ttls: EmoteTtls) -> Self {
+        Self {
+            client: Client::new(),
+            cache: RwLock::new(BttvCache::default()),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            ttls,
+            store: None,
+        }
+    }
+
+    /// Loads emotes fetched before from the store, and keeps emotes fetched from now on in it
+    pub fn with_store(mut self, store: EmoteStore) -> Self {
+        match store.load(EmoteProvider::Bttv, &self.ttls) {
+            Ok(entries) => {
+                let cache = self.cache.get_mut();
+                for entry in entries {
+                    cache.restore(entry, &self.ttls);
+                }
+            }
+            Err(e) => error!("Failed to load cached BTTV emotes: {}", e),
+        }
+        self.store = Some(store);
+        self
+    }
+
+    fn persist(&self, tier: EmoteTier, key: &str, fetched: Option<&FetchedSets>) {
+        if let Some(store) = &self.store {
+            store.save(EmoteProvider::Bttv, tier, key, fetched);
+        }
+    }
+
+    async fn fetch_global_emotes(&self) -> FetchResult<FetchedSets> {
+        let response = self
+            .client
+            .get(format!("{}/cached/emotes/global", self.base_url))
+            .send()
+            .await?;
+        let (emotes, response) = read_json::<Vec<BttvEmote>>(response).await?;
+
+        Ok(Some(FetchedSets {
+            sets: vec![CachedEmoteSet {
+                id: GLOBAL.to_string(),
+                emotes: emotes
+                    .into_iter()
+                    .map(|emote| emote.into_emote("global"))
+                    .collect(),
+            }],
+            response,
+        }))
+    }
+
+    async fn fetch_channel_emotes(&self, channel_id: &str) -> FetchResult<FetchedSets> {
+        let response = self
+            .client
+            .get(format!(
+                "{}/cached/users/twitch/{}",
+                self.base_url, channel_id
+            ))
+            .send()
+            .await?;
+
+        // Channels without a BTTV account have no emotes
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let (data, response) = read_json::<BttvChannelResponse>(response).await?;
+        let emotes = data
+            .channel_emotes
+            .into_iter()
+            .chain(data.shared_emotes)
+            .map(|emote| emote.into_emote(channel_id));
+        Ok(Some(FetchedSets {
+            sets: vec![CachedEmoteSet {
+                id: data.id,
+                emotes: emotes.collect(),
+            }],
+            response,
+        }))
+    }
+
+    /// Stores a fetch, and indexes the emotes for the cache
+    fn index_fetched(
+        &self,
+        tier: EmoteTier,
+        key: &str,
+        result: FetchResult<FetchedSets>,
+    ) -> FetchResult<Arc<EmoteIndex>> {
+        if let Ok(fetched) = &result {
+            self.persist(tier, key, fetched.as_ref());
+        }
+        result.map(|fetched| fetched.map(|fetched| index_sets(&fetched.sets)))
+    }
+}
+
+impl Default for BttvEmoteManager {
+    fn default() -> Self {
+        Self::new(BTTV_API_URL, EmoteTtls::default())
+    }
+}
+
+#[async_trait]
+impl EmoteManager for BttvEmoteManager {
+    fn provider(&self) -> EmoteProvider {
+        EmoteProvider::Bttv
+    }
+
+    /// Gets the cached global and channel emotes. Never fetches.
+    /// Channel emotes are keyed by Twitch channel ID. BTTV has no per-user emotes.
+    async fn get_emotes(&self, scope: &EmoteScope) -> ScopedEmotes {
+        let cache = self.cache.read().await;
+        ScopedEmotes {
+            global: cache.global.get(GLOBAL).cloned().into_iter().collect(),
+            channel: cache
+                .channels
+                .get(&scope.channel_id)
+                .cloned()
+                .into_iter()
+                .collect(),
+            user: vec![],
+        }
+    }
+
+    async fn expire(&self, scope: &EmoteScope) {
+        let mut cache = self.cache.write().await;
+        if scope.channel_id.is_empty() && scope.channel_name.is_empty() {
+            cache.global.expire(GLOBAL);
+        } else if !scope.channel_id.is_empty() {
+            cache.channels.expire(&scope.channel_id);
+        }
+    }
+
+    async fn needs_refresh(&self, scope: &EmoteScope) -> bool {
+        let cache = self.cache.read().await;
+        cache.global.needs_fetch(GLOBAL)
+            || (!scope.channel_id.is_empty() && cache.channels.needs_fetch(&scope.channel_id))
+    }
+
+    /// Fetches the global and channel emotes if they're missing or expired
+    async fn refresh(&self, scope: &EmoteScope) {
+        let channel_id = scope.channel_id.as_str();
+        let (global, channel) = {
+            let mut cache = self.cache.write().await;
+            (
+                cache.global.claim(GLOBAL),
+                !channel_id.is_empty() && cache.channels.claim(channel_id),
+            )
+        };
+
+        if global {
+            let result = self.fetch_global_emotes().await;
+            let result = self.index_fetched(EmoteTier::Global, GLOBAL, result);
+            let fetched = Fetched::from_result(result, "BTTV global emotes");
+            self.cache
+                .write()
+                .await
+                .global
+                .finish(GLOBAL, fetched, &self.ttls);
+        }
+        if channel {
+            let result = self.fetch_channel_emotes(channel_id).await;
+            let result = self.index_fetched(EmoteTier::Channel, channel_id, result);
+            let fetched = Fetched::from_result(result, &format!("BTTV emotes for {}", channel_id));
+            self.cache
+                .write()
+                .await
+                .channels
+                .finish(channel_id, fetched, &self.ttls);
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BttvChannelResponse {
+    id: String,
+    #[serde(default)]
+    channel_emotes: Vec<BttvEmote>,
+    #[serde(default)]
+    shared_emotes: Vec<BttvEmote>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BttvEmote {
+    id: String,
+    code: String,
+    image_type: String,
+    #[serde(default)]
+    animated: bool,
+    /// Whether BTTV marks the emote as a modifier
+    #[serde(default)]
+    modifier: bool,
+}
+
+impl BttvEmote {
+    /// 1x, 2x and 3x images. BTTV doesn't give sizes.
+    fn images(&self) -> EmoteImages {
+        let format = self.image_type.to_lowercase();
+        let scales = (1..=3)
+            .map(|scale| {
+                let url = format!("{}/{}/{}x", BTTV_CDN_URL, self.id, scale);
+                let mut formats = vec![EmoteImageFormat {
+                    format: format.clone(),
+                    animated: self.animated,
+                    url: url.clone(),
+                }];
+                if format != "webp" {
+                    formats.push(EmoteImageFormat {
+                        format: "webp".to_string(),
+                        animated: self.animated,
+                        url: format!("{}.webp", url),
+                    });
+                }
+                EmoteImageScale {
+                    scale,
+                    url,
+                    width: None,
+                    height: None,
+                    formats,
+                }
+            })
+            .collect();
+        EmoteImages {
+            animated: self.animated,
+            scales,
+        }
+    }
+
+    fn into_emote(self, channel: &str) -> Emote {
+        let zero_width = self.modifier || ZERO_WIDTH_EMOTES.contains(&self.code.as_str());
+        let images = self.images();
+        Emote {
+            source: "BetterTTV".to_string(),
+            url: images.urls(),
+            images,
+            id: self.id,
+            name: self.code,
+            channel: channel.to_string(),
+            effect: zero_width.then_some(ZERO_WIDTH_EFFECT),
+            effects: zero_width
+                .then_some(EmoteEffect::ZeroWidth)
+                .into_iter()
+                .collect(),
+            modifier: zero_width,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn emote(image_type: &str, animated: bool) -> BttvEmote {
+        serde_json::from_value(serde_json::json!({
+            "id": "5f1b0186cf6d2144653d2970",
+            "code": "catJAM",
+            "imageType": image_type,
+            "animated": animated
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn images_are_sorted_by_scale() {
+        let images = emote("gif", true).images();
+        let scales: Vec<u8> = images.scales.iter().map(|scale| scale.scale).collect();
+        assert_eq!(scales, [1, 2, 3]);
+        assert_eq!(
+            images.urls(),
+            [
+                "https://cdn.betterttv.net/emote/5f1b0186cf6d2144653d2970/1x",
+                "https://cdn.betterttv.net/emote/5f1b0186cf6d2144653d2970/2x",
+                "https://cdn.betterttv.net/emote/5f1b0186cf6d2144653d2970/3x"
+            ]
+        );
+        assert!(images.scales.iter().all(|scale| scale.width.is_none()));
+    }
+
+    #[test]
+    fn default_image_is_the_native_format() {
+        let images = emote("gif", true).images();
+        assert!(images.animated);
+        let formats = &images.scales[0].formats;
+        assert_eq!(formats[0].format, "gif");
+        assert_eq!(formats[0].url, images.scales[0].url);
+        assert_eq!(formats[1].format, "webp");
+        assert!(formats.iter().all(|format| format.animated));
+    }
+
+    #[test]
+    fn webp_emotes_have_one_format() {
+        let images = emote("webp", false).images();
+        assert!(!images.animated);
+        assert_eq!(images.scales[0].formats.len(), 1);
+        assert!(!images.scales[0].formats[0].animated);
+    }
+}
@@ -0,0 +1,277 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use tracing::warn;
+
+/// How long emote fetch results are kept before they're fetched again
+#[derive(Debug, Clone, Copy)]
+pub struct EmoteTtls {
+    /// For sets that were found. Expired sets are still used while they're fetched again.
+    pub refresh: Duration,
+    /// For sets that don't exist, e.g. a channel without a 7TV account
+    pub missing: Duration,
+    /// For fetches that failed
+    pub failed: Duration,
+}
+
+impl Default for EmoteTtls {
+    fn default() -> Self {
+        Self {
+            refresh: Duration::from_secs(30 * 60),
+            missing: Duration::from_secs(60 * 60),
+            failed: Duration::from_secs(60),
+        }
+    }
+}
+
+/// The outcome of fetching something from an emote provider
+pub enum Fetched<V> {
+    Found(V),
+    /// The provider doesn't have it
+    Missing,
+    /// The request failed
+    Failed,
+}
+
+impl<V> Fetched<V> {
+    /// Converts a fetch result, where `None` means the provider doesn't have it, logging failures
+    pub fn from_result<E: Display>(result: Result<Option<V>, E>, what: &str) -> Self {
+        match result {
+            Ok(Some(value)) => Fetched::Found(value),
+            Ok(None) => Fetched::Missing,
+            Err(e) => {
+                warn!("Failed to fetch {}: {}", what, e);
+                Fetched::Failed
+            }
+        }
+    }
+}
+
+struct CacheEntry<V> {
+    value: Option<V>,
+    /// When to fetch again
+    expires: Instant,
+}
+
+/// Fetch results by key, with TTLs for refreshing found, missing and failed fetches.
+/// Tracks fetches in progress so the same thing isn't fetched twice at once.
+pub struct TtlCache<V> {
+    entries: HashMap<String, CacheEntry<V>>,
+    pending: HashSet<String>,
+}
+
+impl<V> Default for TtlCache<V> {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            pending: HashSet::new(),
+        }
+    }
+}
+
+impl<V> TtlCache<V> {
+    /// Gets a cached value. Expired values are still returned until they're replaced.
+    pub fn get(&self, key: &str) -> Option<&V> {
+        self.entries.get(key)?.value.as_ref()
+    }
+
+    /// Whether a key has never been fetched or has expired, and isn't being fetched
+    pub fn needs_fetch(&self, key: &str) -> bool {
+        if self.pending.contains(key) {
+            return false;
+        }
+        match self.entries.get(key) {
+            Some(entry) => entry.expires <= Instant::now(),
+            None => true,
+        }
+    }
+
+    /// Marks a key as expired, so it's fetched again. The cached value is kept until then.
+    pub fn expire(&mut self, key: &str) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            entry.expires = Instant::now();
+        }
+    }
+
+    /// Claims a fetch for a key if it needs one. Returns false if it doesn't, or someone else is fetching it.
+    pub fn claim(&mut self, key: &str) -> bool {
+        if !self.needs_fetch(key) {
+            return false;
+        }
+        self.pending.insert(key.to_string());
+        true
+    }
+
+    /// Records the result of a claimed fetch. Failed fetches keep the previous value, if any.
+    pub fn finish(&mut self, key: &str, result: Fetched<V>, ttls: &EmoteTtls) {
+        self.pending.remove(key);
+        let now = Instant::now();
+        let entry = match result {
+            Fetched::Found(value) => CacheEntry {
+                value: Some(value),
+                expires: now + ttls.refresh,
+            },
+            Fetched::Missing => CacheEntry {
+                value: None,
+                expires: now + ttls.missing,
+            },
+            Fetched::Failed => CacheEntry {
+                value: self.entries.remove(key).and_then(|entry| entry.value),
+                expires: now + ttls.failed,
+            },
+        };
+        self.entries.insert(key.to_string(), entry);
+    }
+
+    /// Cached values, including expired ones
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.entries
+            .values()
+            .filter_map(|entry| entry.value.as_ref())
+    }
+
+    /// Once there are over `max` entries, drops the ones that expire soonest until `keep` are left,
+    /// returning their values. Fetches in progress aren't counted.
+    pub fn evict(&mut self, max: usize, keep: usize) -> Vec<V> {
+        if self.entries.len() <= max {
+            return vec![];
+        }
+        let mut keys: Vec<(Instant, String)> = self
+            .entries
+            .iter()
+            .map(|(key, entry)| (entry.expires, key.clone()))
+            .collect();
+        keys.sort_unstable();
+        let excess = keys.len().saturating_sub(keep);
+        keys.into_iter()
+            .take(excess)
+            .filter_map(|(_, key)| self.entries.remove(&key)?.value)
+            .collect()
+    }
+
+    /// Restores a value fetched `age` ago, e.g. from the database. `None` means the provider didn't have it.
+    /// It expires when it would have if it had stayed in the cache, so old values are fetched again on first use.
+    pub fn restore(&mut self, key: &str, value: Option<V>, age: Duration, ttls: &EmoteTtls) {
+        let ttl = if value.is_some() {
+            ttls.refresh
+        } else {
+            ttls.missing
+        };
+        let entry = CacheEntry {
+            value,
+            expires: Instant::now() + ttl.saturating_sub(age),
+        };
+        self.entries.insert(key.to_string(), entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPIRED: EmoteTtls = EmoteTtls {
+        refresh: Duration::ZERO,
+        missing: Duration::ZERO,
+        failed: Duration::ZERO,
+    };
+
+    fn ttls() -> EmoteTtls {
+        EmoteTtls::default()
+    }
+
+    #[test]
+    fn claims_once_until_finished() {
+        let mut cache = TtlCache::default();
+        assert!(cache.needs_fetch("a"));
+        assert!(cache.claim("a"));
+        assert!(!cache.needs_fetch("a"));
+        assert!(!cache.claim("a"));
+
+        cache.finish("a", Fetched::Found(1), &ttls());
+        assert_eq!(cache.get("a"), Some(&1));
+        assert!(!cache.needs_fetch("a"));
+        assert!(!cache.claim("a"));
+    }
+
+    #[test]
+    fn expired_values_are_kept_until_refetched() {
+        let mut cache = TtlCache::default();
+        assert!(cache.claim("a"));
+        cache.finish("a", Fetched::Found(1), &ttls());
+
+        cache.expire("a");
+        assert!(cache.needs_fetch("a"));
+        assert_eq!(cache.get("a"), Some(&1));
+
+        assert!(cache.claim("a"));
+        cache.finish("a", Fetched::Found(2), &ttls());
+        assert_eq!(cache.get("a"), Some(&2));
+    }
+
+    #[test]
+    fn ttls_depend_on_the_outcome() {
+        let mut cache = TtlCache::default();
+        let ttls = EmoteTtls {
+            failed: Duration::ZERO,
+            ..ttls()
+        };
+        cache.claim("found");
+        cache.finish("found", Fetched::Found(1), &ttls);
+        cache.claim("missing");
+        cache.finish("missing", Fetched::<i32>::Missing, &ttls);
+        cache.claim("failed");
+        cache.finish("failed", Fetched::Failed, &ttls);
+
+        assert!(!cache.needs_fetch("found"));
+        assert!(!cache.needs_fetch("missing"));
+        assert_eq!(cache.get("missing"), None);
+        assert!(cache.needs_fetch("failed"));
+    }
+
+    #[test]
+    fn failed_fetches_keep_the_previous_value() {
+        let mut cache = TtlCache::default();
+        cache.claim("a");
+        cache.finish("a", Fetched::Found(1), &EXPIRED);
+        assert!(cache.claim("a"));
+        cache.finish("a", Fetched::Failed, &ttls());
+        assert_eq!(cache.get("a"), Some(&1));
+        assert!(!cache.needs_fetch("a"));
+    }
+
+    #[test]
+    fn restored_values_expire_by_age() {
+        let mut cache = TtlCache::default();
+        let ttls = ttls();
+        cache.restore("fresh", Some(1), Duration::ZERO, &ttls);
+        cache.restore("stale", Some(2), ttls.refresh, &ttls);
+        cache.restore("missing", None, Duration::ZERO, &ttls);
+
+        assert!(!cache.needs_fetch("fresh"));
+        assert!(cache.needs_fetch("stale"));
+        assert_eq!(cache.get("stale"), Some(&2));
+        assert!(!cache.needs_fetch("missing"));
+        assert_eq!(cache.get("missing"), None);
+    }
+
+    #[test]
+    fn evicts_the_soonest_to_expire() {
+        let mut cache = TtlCache::default();
+        let ttls = ttls();
+        for i in 0..5u64 {
+            // Older values expire sooner
+            cache.restore(&i.to_string(), Some(i), Duration::from_secs(i * 60), &ttls);
+        }
+        assert!(cache.evict(5, 3).is_empty());
+
+        let mut evicted = cache.evict(4, 3);
+        evicted.sort_unstable();
+        assert_eq!(evicted, vec![3, 4]);
+        let mut kept: Vec<u64> = cache.values().copied().collect();
+        kept.sort_unstable();
+        assert_eq!(kept, vec![0, 1, 2]);
+    }
+}
@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+
+use super::ZERO_WIDTH_EFFECT;
+
+/// Something an emote does to the emote before it, decoded from `Emote::effect`.
+/// FFZ modifier emotes can have several; 7TV and BTTV zero-width emotes are `ZeroWidth`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EmoteEffect {
+    /// Drawn over the emote before it
+    ZeroWidth,
+    /// The modifier itself isn't drawn, it only transforms the emote before it
+    Hidden,
+    FlipX,
+    FlipY,
+    GrowX,
+    Slide,
+    /// Slides in when the message appears
+    Appear,
+    /// Slides out
+    Leave,
+    Rotate45,
+    Rotate90,
+    GrowY,
+    ShrinkX,
+    Rainbow,
+    HyperRed,
+    HyperShake,
+    Cursed,
+    Jam,
+    Bounce,
+}
+
+impl EmoteEffect {
+    pub const ALL: [EmoteEffect; 18] = [
+        EmoteEffect::ZeroWidth,
+        EmoteEffect::Hidden,
+        EmoteEffect::FlipX,
+        EmoteEffect::FlipY,
+        EmoteEffect::GrowX,
+        EmoteEffect::Slide,
+        EmoteEffect::Appear,
+        EmoteEffect::Leave,
+        EmoteEffect::Rotate45,
+        EmoteEffect::Rotate90,
+        EmoteEffect::GrowY,
+        EmoteEffect::ShrinkX,
+        EmoteEffect::Rainbow,
+        EmoteEffect::HyperRed,
+        EmoteEffect::HyperShake,
+        EmoteEffect::Cursed,
+        EmoteEffect::Jam,
+        EmoteEffect::Bounce,
+    ];
+
+    /// The effect's bit in `Emote::effect`. These are FFZ's modifier flags, plus one for zero-width.
+    pub fn flag(self) -> i64 {
+        match self {
+            EmoteEffect::ZeroWidth => ZERO_WIDTH_EFFECT,
+            EmoteEffect::Hidden => 1 << 0,
+            EmoteEffect::FlipX => 1 << 1,
+            EmoteEffect::FlipY => 1 << 2,
+            EmoteEffect::GrowX => 1 << 3,
+            EmoteEffect::Slide => 1 << 4,
+            EmoteEffect::Appear => 1 << 5,
+            EmoteEffect::Leave => 1 << 6,
+            EmoteEffect::Rotate45 => 1 << 7,
+            EmoteEffect::Rotate90 => 1 << 8,
+            EmoteEffect::GrowY => 1 << 9,
+            EmoteEffect::ShrinkX => 1 << 10,
+            EmoteEffect::Rainbow => 1 << 11,
+            EmoteEffect::HyperRed => 1 << 12,
+            EmoteEffect::HyperShake => 1 << 13,
+            EmoteEffect::Cursed => 1 << 14,
+            EmoteEffect::Jam => 1 << 15,
+            EmoteEffect::Bounce => 1 << 16,
+        }
+    }
+
+    /// Decodes effect flags. Flags we don't know are ignored.
+    pub fn from_flags(flags: i64) -> Vec<EmoteEffect> {
+        Self::ALL
+            .into_iter()
+            .filter(|effect| flags & effect.flag() != 0)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn flags_are_distinct_single_bits() {
+        let flags: HashSet<i64> = EmoteEffect::ALL.iter().map(|e| e.flag()).collect();
+        assert_eq!(flags.len(), EmoteEffect::ALL.len());
+        assert!(flags.iter().all(|flag| flag.count_ones() == 1));
+    }
+
+    #[test]
+    fn decodes_each_flag() {
+        for effect in EmoteEffect::ALL {
+            assert_eq!(EmoteEffect::from_flags(effect.flag()), vec![effect]);
+        }
+    }
+
+    #[test]
+    fn decodes_combined_flags_in_order() {
+        let flags = EmoteEffect::Rainbow.flag() | EmoteEffect::FlipX.flag() | ZERO_WIDTH_EFFECT;
+        assert_eq!(
+            EmoteEffect::from_flags(flags),
+            vec![
+                EmoteEffect::ZeroWidth,
+                EmoteEffect::FlipX,
+                EmoteEffect::Rainbow
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_unknown_flags() {
+        assert!(EmoteEffect::from_flags(0).is_empty());
+        assert!(EmoteEffect::from_flags(1 << 20).is_empty());
+        assert_eq!(
+            EmoteEffect::from_flags(1 << 20 | EmoteEffect::Hidden.flag()),
+            vec![EmoteEffect::Hidden]
+        );
+    }
+}
@@ -1,54 +1,125 @@
 use async_trait::async_trait;
 use std::collections::HashMap;
+use std::sync::Arc;
 
 /// Gets FrankerFaceZ emotes
+use crate::db::EmoteCacheEntry;
+use crate::twitch::emote::cache::{EmoteTtls, Fetched, TtlCache};
+use crate::twitch::emote::effects::EmoteEffect;
+use crate::twitch::emote::images::{EmoteImageFormat, EmoteImageScale, EmoteImages};
+use crate::twitch::emote::index::{EmoteIndex, EmoteProvider, EmoteTier, ScopedEmotes};
+use crate::twitch::emote::store::{read_json, CachedEmoteSet, EmoteStore, FetchedSets};
 use crate::twitch::emote::Emote;
 use crate::twitch::emote::EmoteManager;
+use crate::twitch::emote::EmoteScope;
 use reqwest::Client;
 use serde::Deserialize;
 use serde::Serialize;
+use tokio::sync::RwLock;
+use tracing::error;
+
+type FetchResult<T> = Result<Option<T>, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Cache key for the default sets
+const GLOBAL: &str = "@global";
+/// Where FFZ serves emote images
+pub const FFZ_CDN_URL: &str = "https://cdn.frankerfacez.com/emote";
 
 pub struct FrankerFaceZEmoteManager {
-    // Emote caches
-    /// Set cache
-    set: HashMap<String, Vec<Emote>>,
-    /// Channel-Set cache
-    emotes: HashMap<String, String>,
-    /// User-Set cache
-    user_sets: HashMap<String, Vec<String>>,
+    /// Emote caches. Only locked while reading or updating, never during requests.
+    cache: RwLock<FFZCache>,
     client: Client,
+    ttls: EmoteTtls,
+    /// Where fetched sets are kept across restarts
+    store: Option<EmoteStore>,
+}
+
+#[derive(Default)]
+struct FFZCache {
+    /// Set ID -> emotes by name
+    sets: HashMap<String, Arc<EmoteIndex>>,
+    /// Default sets
+    global: TtlCache<Vec<String>>,
+    /// Channel -> sets
+    channels: TtlCache<Vec<String>>,
+    /// User -> sets
+    user_sets: TtlCache<Vec<String>>,
+}
+
+impl FFZCache {
+    fn cache_sets(&mut self, sets: &[CachedEmoteSet]) -> Vec<String> {
+        sets.iter()
+            .map(|set| {
+                self.sets.insert(set.id.clone(), set.index());
+                set.id.clone()
+            })
+            .collect()
+    }
+
+    /// Restores a fetch from the emote store
+    fn restore(&mut self, entry: EmoteCacheEntry, ttls: &EmoteTtls) {
+        let Ok(tier) = entry.tier.parse::<EmoteTier>() else {
+            return;
+        };
+        let ids = (!entry.missing).then(|| self.cache_sets(&entry.sets));
+        let cache = match tier {
+            EmoteTier::Global => &mut self.global,
+            EmoteTier::Channel => &mut self.channels,
+            EmoteTier::User => &mut self.user_sets,
+        };
+        cache.restore(&entry.scope_key, ids, entry.age(), ttls);
+    }
+
+    fn resolve(&self, set_ids: Option<&Vec<String>>) -> Vec<Arc<EmoteIndex>> {
+        set_ids
+            .into_iter()
+            .flatten()
+            .filter_map(|id| self.sets.get(id).cloned())
+            .collect()
+    }
 }
 
 impl FrankerFaceZEmoteManager {
-    pub fn new() -> Self {
+    pub fn new(ttls: EmoteTtls) -> Self {
         Self {
             client: Client::new(),
-            set: HashMap::new(),
-            emotes: HashMap::new(),
-            user_sets: HashMap::new(),
+            cache: RwLock::new(FFZCache::default()),
+            ttls,
+            store: None,
         }
     }
-    fn cache_sets(&mut self, sets: HashMap<String, EmoteSet>) {
-        for set in sets {
-            // Convert FFZEmote to your Emote type
-            let emotes: Vec<Emote> = set
-                .1
-                .emoticons
-                .iter()
-                .map(|ffz_emote| Emote {
-                    source: "FrankerFaceZ".to_string(),
-                    id: ffz_emote.id.to_string(),
-                    name: ffz_emote.name.clone(),
-                    channel: "global".to_string(),
-                    url: ffz_emote.urls.values().map(|v| v.to_owned()).collect(),
-                    effect: Some(ffz_emote.modifier_flags),
-                })
-                .collect();
 
-            self.set.insert(set.0, emotes);
+    /// Loads sets fetched before from the store, and keeps sets fetched from now on in it
+    pub fn with_store(mut self, store: EmoteStore) -> Self {
+        match store.load(EmoteProvider::Ffz, &self.ttls) {
+            Ok(entries) => {
+                let cache = self.cache.get_mut();
+                for entry in entries {
+                    cache.restore(entry, &self.ttls);
+                }
+            }
+            Err(e) => error!("Failed to load cached FFZ emotes: {}", e),
         }
+        self.store = Some(store);
+        self
     }
-    async fn fetch_user_sets(&mut self, user: String) -> Result<(), Box<dyn std::error::Error>> {
+
+    fn persist(&self, tier: EmoteTier, key: &str, fetched: Option<&FetchedSets>) {
+        if let Some(store) = &self.store {
+            store.save(EmoteProvider::Ffz, tier, key, fetched);
+        }
+    }
+
+    async fn fetch_global_sets(&self) -> FetchResult<(FFZGlobalEmoteResponse, String)> {
+        let response = self
+            .client
+            .get("https://api.frankerfacez.com/v1/set/global")
+            .send()
+            .await?;
+        Ok(Some(read_json(response).await?))
+    }
+
+    async fn fetch_user_sets(&self, user: &str) -> FetchResult<FetchedSets> {
         let response = self
             .client
             .get(format!("https://api.frankerfacez.com/v1/user/{}", user))
@@ -57,127 +128,159 @@ impl FrankerFaceZEmoteManager {
 
         // Check if response is 404
         if response.status() == reqwest::StatusCode::NOT_FOUND {
-            // Insert null set for user
-            self.user_sets.insert(user, vec![]);
-            return Ok(());
+            return Ok(None);
         }
 
-        let data = response.json::<FFZSetsResponse>().await?;
-        let keys = data.sets.keys().map(|s| s.to_owned()).collect();
-        self.cache_sets(data.sets);
-        self.user_sets.insert(user, keys);
-        Ok(())
+        let (data, response) = read_json::<FFZSetsResponse>(response).await?;
+        Ok(Some(data.into_fetched(response)))
     }
 
-    async fn fetch_channel_sets(
-        &mut self,
-        channel: String,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let response = self
-            .client
-            .get(format!("https://api.frankerfacez.com/v1/room/{}", channel))
-            .send()
-            .await?;
+    async fn fetch_channel_sets(&self, scope: &EmoteScope) -> FetchResult<FetchedSets> {
+        // Shared Chat messages may only carry the channel ID
+        let url = if scope.channel_name.is_empty() {
+            format!(
+                "https://api.frankerfacez.com/v1/room/id/{}",
+                scope.channel_id
+            )
+        } else {
+            format!(
+                "https://api.frankerfacez.com/v1/room/{}",
+                scope.channel_name
+            )
+        };
+        let response = self.client.get(url).send().await?;
 
-        // Check if response is 404
+        // Channels without an FFZ room have no emotes
         if response.status() == reqwest::StatusCode::NOT_FOUND {
-            // Handle 404 case - channel not found
-            return Ok(()); // or return an appropriate error
+            return Ok(None);
         }
 
-        let data = response.json::<FFZSetsResponse>().await?;
-        let key = data
-            .sets
-            .keys()
-            .next()
-            .cloned()
-            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "No sets found"))?;
-        self.cache_sets(data.sets);
-        self.emotes.insert(channel, key);
-        Ok(())
+        let (data, response) = read_json::<FFZSetsResponse>(response).await?;
+        Ok(Some(data.into_fetched(response)))
+    }
+
+    /// Caches the default sets, and the sets FFZ gives specific users
+    async fn store_global(&self, result: FetchResult<(FFZGlobalEmoteResponse, String)>) {
+        let mut cache = self.cache.write().await;
+        let result = result.map(|response| {
+            response.map(|(data, response)| {
+                let sets: HashMap<String, CachedEmoteSet> = data
+                    .sets
+                    .into_iter()
+                    .map(|(id, set)| (id.clone(), set.into_cached(id)))
+                    .collect();
+                let pick = |ids: Vec<String>| -> Vec<CachedEmoteSet> {
+                    ids.iter().filter_map(|id| sets.get(id).cloned()).collect()
+                };
+
+                // FFZ lists the users of each set, we want the sets of each user
+                let mut users: HashMap<String, Vec<String>> = HashMap::new();
+                for (set_id, logins) in data.users {
+                    for login in logins {
+                        users.entry(login).or_default().push(set_id.clone());
+                    }
+                }
+
+                for (user, user_sets) in users {
+                    let user_sets = FetchedSets {
+                        sets: pick(user_sets),
+                        response: String::new(),
+                    };
+                    self.persist(EmoteTier::User, &user, Some(&user_sets));
+                    let ids = cache.cache_sets(&user_sets.sets);
+                    cache
+                        .user_sets
+                        .finish(&user, Fetched::Found(ids), &self.ttls);
+                }
+
+                let default_sets = FetchedSets {
+                    sets: pick(data.default_sets.iter().map(|id| id.to_string()).collect()),
+                    response,
+                };
+                self.persist(EmoteTier::Global, GLOBAL, Some(&default_sets));
+                cache.cache_sets(&default_sets.sets)
+            })
+        });
+        let fetched = Fetched::from_result(result, "FFZ global emotes");
+        cache.global.finish(GLOBAL, fetched, &self.ttls);
     }
 }
 
 impl Default for FrankerFaceZEmoteManager {
     fn default() -> Self {
-        Self::new()
+        Self::new(EmoteTtls::default())
     }
 }
 
 #[async_trait]
 impl EmoteManager for FrankerFaceZEmoteManager {
-    async fn fetch(&mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let response = self
-            .client
-            .get("https://api.frankerfacez.com/v1/set/global")
-            .send()
-            .await?
-            .json::<FFZGlobalEmoteResponse>()
-            .await?;
+    fn provider(&self) -> EmoteProvider {
+        EmoteProvider::Ffz
+    }
 
-        // Cache default sets
-        self.cache_sets(response.sets);
-        // Cache user sets
-        for (user_id, user_sets) in response.users {
-            self.user_sets.insert(
-                user_id,
-                user_sets.into_iter().map(|s| s.parse().unwrap()).collect(),
-            );
-        }
-        // Cache global sets (as username "@global")
-        for set in response.default_sets {
-            self.emotes.insert("@global".to_string(), set.to_string());
+    /// Gets the cached default, channel and user sets. Never fetches.
+    async fn get_emotes(&self, scope: &EmoteScope) -> ScopedEmotes {
+        let cache = self.cache.read().await;
+        ScopedEmotes {
+            global: cache.resolve(cache.global.get(GLOBAL)),
+            channel: cache.resolve(cache.channels.get(scope.channel_key())),
+            user: cache.resolve(cache.user_sets.get(&scope.user_name)),
         }
-
-        Ok(())
     }
 
-    /// Fetches the allowed emote sets for a user. Mutable because we may add new sets.
-    async fn get_emote(&mut self, user_name: &str, channel_name: &str, id: &str) -> Option<Emote> {
-        // check global emotes
-        if let Some(channel) = self.emotes.get("@global") {
-            if let Some(set) = self.set.get(channel) {
-                return set.iter().find(|emote| emote.id == id).cloned();
-            }
+    async fn expire(&self, scope: &EmoteScope) {
+        let mut cache = self.cache.write().await;
+        match scope.channel_key() {
+            "" => cache.global.expire(GLOBAL),
+            channel_key => cache.channels.expire(channel_key),
         }
+    }
 
-        // check channel emotes
-        let channel_emotes = match self.emotes.get(channel_name) {
-            Some(channel) => Some(channel),
-            None => {
-                // fetch channel sets
-                match self.fetch_channel_sets(channel_name.to_string()).await {
-                    Ok(_) => self.emotes.get(channel_name),
-                    Err(_) => None,
-                }
-            }
+    async fn needs_refresh(&self, scope: &EmoteScope) -> bool {
+        let cache = self.cache.read().await;
+        cache.global.needs_fetch(GLOBAL)
+            || (!scope.channel_key().is_empty() && cache.channels.needs_fetch(scope.channel_key()))
+            || (!scope.user_name.is_empty() && cache.user_sets.needs_fetch(&scope.user_name))
+    }
+
+    /// Fetches the default, channel and user sets that are missing or expired.
+    /// The cache lock is released while fetching, so lookups aren't held up.
+    async fn refresh(&self, scope: &EmoteScope) {
+        let channel_key = scope.channel_key();
+        let user_name = scope.user_name.as_str();
+        let (global, channel, user) = {
+            let mut cache = self.cache.write().await;
+            (
+                cache.global.claim(GLOBAL),
+                !channel_key.is_empty() && cache.channels.claim(channel_key),
+                !user_name.is_empty() && cache.user_sets.claim(user_name),
+            )
         };
-        if let Some(channel) = channel_emotes {
-            if let Some(set) = self.set.get(channel) {
-                return set.iter().find(|emote| emote.id == id).cloned();
-            }
-        }
 
-        // check user emotes
-        let user_set = match self.user_sets.get(user_name) {
-            Some(user_set) => Some(user_set),
-            None => {
-                // fetch user sets
-                match self.fetch_user_sets(user_name.to_string()).await {
-                    Ok(_) => self.user_sets.get(user_name),
-                    Err(_) => None,
-                }
+        if global {
+            let result = self.fetch_global_sets().await;
+            self.store_global(result).await;
+        }
+        if channel {
+            let result = self.fetch_channel_sets(scope).await;
+            if let Ok(fetched) = &result {
+                self.persist(EmoteTier::Channel, channel_key, fetched.as_ref());
             }
-        };
-        if let Some(user_set) = user_set {
-            for setid in user_set {
-                // fetch set
-                if let Some(set) = self.set.get(setid) {
-                    return set.iter().find(|emote| emote.id == id).cloned();
-                }
+            let mut cache = self.cache.write().await;
+            let result = result.map(|data| data.map(|data| cache.cache_sets(&data.sets)));
+            let fetched = Fetched::from_result(result, &format!("FFZ emotes for {}", channel_key));
+            cache.channels.finish(channel_key, fetched, &self.ttls);
+        }
+        if user {
+            let result = self.fetch_user_sets(user_name).await;
+            if let Ok(fetched) = &result {
+                self.persist(EmoteTier::User, user_name, fetched.as_ref());
             }
+            let mut cache = self.cache.write().await;
+            let result = result.map(|data| data.map(|data| cache.cache_sets(&data.sets)));
+            let fetched = Fetched::from_result(result, &format!("FFZ emotes for {}", user_name));
+            cache.user_sets.finish(user_name, fetched, &self.ttls);
         }
-        None
     }
 }
 
@@ -186,10 +289,24 @@ struct FFZSetsResponse {
     sets: HashMap<String, EmoteSet>,
 }
 
+impl FFZSetsResponse {
+    fn into_fetched(self, response: String) -> FetchedSets {
+        FetchedSets {
+            sets: self
+                .sets
+                .into_iter()
+                .map(|(id, set)| set.into_cached(id))
+                .collect(),
+            response,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct FFZGlobalEmoteResponse {
     default_sets: Vec<i64>,
     sets: HashMap<String, EmoteSet>,
+    /// Set ID -> logins of the users the set is given to
     users: HashMap<String, Vec<String>>,
 }
 
@@ -204,6 +321,29 @@ struct EmoteSet {
     emoticons: Vec<FFZEmote>,
 }
 
+impl EmoteSet {
+    fn into_cached(self, id: String) -> CachedEmoteSet {
+        let emotes = self.emoticons.into_iter().map(|ffz_emote| {
+            let images = ffz_emote.images();
+            Emote {
+                source: "FrankerFaceZ".to_string(),
+                id: ffz_emote.id.to_string(),
+                name: ffz_emote.name,
+                channel: "global".to_string(),
+                url: images.urls(),
+                images,
+                effect: (ffz_emote.modifier_flags != 0).then_some(ffz_emote.modifier_flags),
+                effects: EmoteEffect::from_flags(ffz_emote.modifier_flags),
+                modifier: ffz_emote.modifier,
+            }
+        });
+        CachedEmoteSet {
+            id,
+            emotes: emotes.collect(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct FFZEmote {
     id: i64,
@@ -220,13 +360,56 @@ struct FFZEmote {
     css: Option<String>,
     owner: Owner,
     artist: Option<serde_json::Value>,
+    /// Static images by scale (`1`, `2` or `4`)
     urls: HashMap<String, String>,
+    /// Animated images by scale, for animated emotes
+    #[serde(default)]
+    animated: Option<HashMap<String, String>>,
     status: i32,
     usage_count: i32,
     created_at: String,
     last_updated: Option<String>,
 }
 
+impl FFZEmote {
+    /// Images by scale. Static images are PNGs, animated ones WebPs.
+    fn images(&self) -> EmoteImages {
+        let mut scales: Vec<EmoteImageScale> = self
+            .urls
+            .iter()
+            .filter_map(|(scale, url)| {
+                let animated = self.animated.as_ref().and_then(|urls| urls.get(scale));
+                let scale: u8 = scale.parse().ok()?;
+                let mut formats = vec![EmoteImageFormat {
+                    format: "png".to_string(),
+                    animated: false,
+                    url: url.clone(),
+                }];
+                if let Some(animated) = animated {
+                    formats.push(EmoteImageFormat {
+                        format: "webp".to_string(),
+                        animated: true,
+                        url: animated.clone(),
+                    });
+                }
+                // Sizes are given at 1x
+                Some(EmoteImageScale {
+                    scale,
+                    url: animated.unwrap_or(url).clone(),
+                    width: Some(self.width as u32 * scale as u32),
+                    height: Some(self.height as u32 * scale as u32),
+                    formats,
+                })
+            })
+            .collect();
+        scales.sort_by_key(|scale| scale.scale);
+        EmoteImages {
+            animated: self.animated.is_some(),
+            scales,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Owner {
     #[serde(rename = "_id")]
@@ -234,3 +417,100 @@ struct Owner {
     name: String,
     display_name: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn emote(animated: bool) -> FFZEmote {
+        let mut emote = serde_json::json!({
+            "id": 720507,
+            "name": "catJAM",
+            "height": 28,
+            "width": 32,
+            "public": true,
+            "hidden": false,
+            "modifier": false,
+            "modifier_flags": 0,
+            "owner": {"_id": 1, "name": "owner", "display_name": "Owner"},
+            "urls": {
+                "4": "https://cdn.frankerfacez.com/emote/720507/4",
+                "1": "https://cdn.frankerfacez.com/emote/720507/1",
+                "2": "https://cdn.frankerfacez.com/emote/720507/2"
+            },
+            "status": 1,
+            "usage_count": 1,
+            "created_at": "2021-01-01T00:00:00.000Z"
+        });
+        if animated {
+            emote["animated"] = serde_json::json!({
+                "1": "https://cdn.frankerfacez.com/emote/720507/animated/1",
+                "2": "https://cdn.frankerfacez.com/emote/720507/animated/2",
+                "4": "https://cdn.frankerfacez.com/emote/720507/animated/4"
+            });
+        }
+        serde_json::from_value(emote).unwrap()
+    }
+
+    #[test]
+    fn images_are_sorted_by_scale() {
+        let images = emote(false).images();
+        let scales: Vec<u8> = images.scales.iter().map(|scale| scale.scale).collect();
+        assert_eq!(scales, [1, 2, 4]);
+        assert_eq!(
+            images.urls(),
+            [
+                "https://cdn.frankerfacez.com/emote/720507/1",
+                "https://cdn.frankerfacez.com/emote/720507/2",
+                "https://cdn.frankerfacez.com/emote/720507/4"
+            ]
+        );
+    }
+
+    #[test]
+    fn sizes_scale_from_1x() {
+        let images = emote(false).images();
+        let sizes: Vec<_> = images
+            .scales
+            .iter()
+            .map(|scale| (scale.width, scale.height))
+            .collect();
+        assert_eq!(
+            sizes,
+            [
+                (Some(32), Some(28)),
+                (Some(64), Some(56)),
+                (Some(128), Some(112))
+            ]
+        );
+    }
+
+    #[test]
+    fn static_emotes_default_to_png() {
+        let images = emote(false).images();
+        assert!(!images.animated);
+        let scale = &images.scales[0];
+        assert_eq!(scale.formats.len(), 1);
+        assert_eq!(scale.formats[0].format, "png");
+        assert!(!scale.formats[0].animated);
+    }
+
+    #[test]
+    fn animated_emotes_default_to_animated_webp() {
+        let images = emote(true).images();
+        assert!(images.animated);
+        let scale = &images.scales[1];
+        assert_eq!(
+            scale.url,
+            "https://cdn.frankerfacez.com/emote/720507/animated/2"
+        );
+        assert!(scale
+            .formats
+            .iter()
+            .any(|format| format.format == "png" && !format.animated));
+        assert!(scale
+            .formats
+            .iter()
+            .any(|format| format.format == "webp" && format.animated && format.url == scale.url));
+    }
+}
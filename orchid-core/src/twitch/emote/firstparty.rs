@@ -4,6 +4,7 @@ use std::collections::HashMap;
 /// Gets FrankerFaceZ emotes
 use crate::twitch::emote::Emote;
 use crate::twitch::emote::EmoteManager;
+use crate::twitch::emote::EmoteScope;
 use reqwest::Client;
 use serde::Deserialize;
 use serde::Serialize;
@@ -20,7 +21,7 @@ impl Default for FirstPartyEmoteManager {
 
 #[async_trait]
 impl EmoteManager for FrankerFaceZEmoteManager {
-    async fn fetch(&mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    async fn fetch(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let response = self
             .client
             .get("https://api.frankerfacez.com/v1/set/global")
@@ -47,7 +48,10 @@ impl EmoteManager for FrankerFaceZEmoteManager {
     }
 
     /// Fetches the allowed emote sets for a user. Mutable because we may add new sets.
-    async fn get_emote(&mut self, user_name: &str, channel_name: &str, id: &str) -> Option<Emote> {
+    async fn get_emote(&self, scope: &EmoteScope, id: &str) -> Option<Emote> {
+        let channel_key = scope.channel_key();
+        let user_name = scope.user_name.as_str();
+
         // check global emotes
         if let Some(channel) = self.emotes.get("@global") {
             if let Some(set) = self.set.get(channel) {
@@ -56,12 +60,12 @@ impl EmoteManager for FrankerFaceZEmoteManager {
         }
 
         // check channel emotes
-        let channel_emotes = match self.emotes.get(channel_name) {
+        let channel_emotes = match self.emotes.get(channel_key) {
             Some(channel) => Some(channel),
             None => {
                 // fetch channel sets
-                match self.fetch_channel_sets(channel_name.to_string()).await {
-                    Ok(_) => self.emotes.get(channel_name),
+                match self.fetch_channel_sets(scope).await {
+                    Ok(_) => self.emotes.get(channel_key),
                     Err(_) => None,
                 }
             }
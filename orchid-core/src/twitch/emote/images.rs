@@ -0,0 +1,352 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use super::{bttv::BTTV_CDN_URL, index::EmoteProvider};
+use crate::err::{OrchidError, OrchidResult};
+
+/// Image types we'll store, with the file extension they're stored under
+const IMAGE_TYPES: &[(&str, &str)] = &[
+    ("image/webp", "webp"),
+    ("image/avif", "avif"),
+    ("image/gif", "gif"),
+    ("image/png", "png"),
+];
+
+/// Longest emote ID we'll look up
+const MAX_ID_LEN: usize = 64;
+
+/// Where the providers serve emote images, so the proxy fetches from the same place the emotes point at
+#[derive(Debug, Clone)]
+pub struct EmoteCdns {
+    pub seventv: String,
+    pub ffz: String,
+}
+
+impl EmoteCdns {
+    /// Where a provider serves an emote image at a scale, if it has that scale
+    fn upstream_url(
+        &self,
+        provider: EmoteProvider,
+        id: &str,
+        scale: u8,
+        animated: bool,
+    ) -> Option<String> {
+        if !has_scale(provider, scale) {
+            return None;
+        }
+        Some(match provider {
+            EmoteProvider::Twitch => format!(
+                "https://static-cdn.jtvnw.net/emoticons/v2/{}/default/dark/{}.0",
+                id, scale
+            ),
+            EmoteProvider::SevenTv => format!("{}/{}/{}x.webp", self.seventv, id, scale),
+            EmoteProvider::Bttv => format!("{}/{}/{}x", BTTV_CDN_URL, id, scale),
+            EmoteProvider::Ffz if animated => format!("{}/{}/animated/{}", self.ffz, id, scale),
+            EmoteProvider::Ffz => format!("{}/{}/{}", self.ffz, id, scale),
+        })
+    }
+}
+
+/// An emote's images at each scale
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EmoteImages {
+    pub animated: bool,
+    /// Smallest scale first
+    pub scales: Vec<EmoteImageScale>,
+}
+
+impl EmoteImages {
+    /// The default image at each scale, smallest first
+    pub fn urls(&self) -> Vec<String> {
+        self.scales.iter().map(|scale| scale.url.clone()).collect()
+    }
+}
+
+/// An emote's image at one scale, in each format the provider has it in
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EmoteImageScale {
+    /// 1 for 1x, 2 for 2x and so on
+    pub scale: u8,
+    /// The image to use by default. Animated if the emote is.
+    pub url: String,
+    /// Size in pixels, if the provider gives it
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Every format the image is available in
+    pub formats: Vec<EmoteImageFormat>,
+}
+
+/// One format of an emote image
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EmoteImageFormat {
+    /// `webp`, `avif`, `gif` or `png`
+    pub format: String,
+    pub animated: bool,
+    pub url: String,
+}
+
+/// An emote image, ready to serve
+pub struct EmoteImage {
+    pub content_type: &'static str,
+    pub bytes: Vec<u8>,
+}
+
+/// Fetches emote images from the providers' CDNs and keeps them on disk,
+/// at `<dir>/<provider>/<id>-<scale>.<ext>` (`<id>-<scale>-animated.<ext>` for separate animated images),
+/// so each image is only fetched once.
+pub struct EmoteImageCache {
+    dir: PathBuf,
+    /// Largest image we'll fetch, in bytes
+    max_bytes: usize,
+    /// Only serve images already on disk
+    offline: bool,
+    cdns: EmoteCdns,
+    client: Client,
+    /// Image -> lock held while it's fetched, so it isn't fetched twice at once
+    fetches: std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl EmoteImageCache {
+    pub fn new(dir: PathBuf, max_bytes: usize, offline: bool, cdns: EmoteCdns) -> Self {
+        Self {
+            dir,
+            max_bytes,
+            offline,
+            cdns,
+            client: Client::new(),
+            fetches: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Gets an emote image from disk, fetching it first if we don't have it.
+    /// `animated` asks for the animated image of a provider that keeps it apart from the static one.
+    pub async fn get(
+        &self,
+        provider: EmoteProvider,
+        id: &str,
+        scale: u8,
+        animated: bool,
+    ) -> OrchidResult<EmoteImage> {
+        if id.is_empty()
+            || id.len() > MAX_ID_LEN
+            || !id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(OrchidError::InvalidQuery(format!(
+                "Invalid emote ID: {}",
+                id
+            )));
+        }
+        if animated && !has_separate_animated(provider) {
+            return Err(OrchidError::InvalidQuery(format!(
+                "{} emotes have no separate animated images",
+                provider
+            )));
+        }
+        let url = self
+            .cdns
+            .upstream_url(provider, id, scale, animated)
+            .ok_or_else(|| {
+                OrchidError::InvalidQuery(format!("{} emotes have no scale {}", provider, scale))
+            })?;
+
+        let dir = self.dir.join(provider.to_string());
+        let name = if animated {
+            format!("{}-{}-animated", id, scale)
+        } else {
+            format!("{}-{}", id, scale)
+        };
+        if let Some(image) = read_image(&dir, &name).await? {
+            return Ok(image);
+        }
+        if self.offline {
+            return Err(OrchidError::NotFound(format!("{} emote {}", provider, id)));
+        }
+
+        let key = format!("{}/{}", provider, name);
+        let lock = self
+            .fetches
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let result = {
+            let _fetching = lock.lock().await;
+            // It may have been fetched while we waited
+            match read_image(&dir, &name).await {
+                Ok(Some(image)) => Ok(image),
+                Ok(None) => self.fetch(&url, &dir, &name).await,
+                Err(e) => Err(e),
+            }
+        };
+        self.fetches.lock().unwrap().remove(&key);
+        result
+    }
+
+    async fn fetch(&self, url: &str, dir: &Path, name: &str) -> OrchidResult<EmoteImage> {
+        let upstream = |e: reqwest::Error| OrchidError::UpstreamError(e.to_string());
+        let response = self.client.get(url).send().await.map_err(upstream)?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(OrchidError::NotFound(url.to_string()));
+        }
+        let mut response = response.error_for_status().map_err(upstream)?;
+
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let Some(&(content_type, extension)) = IMAGE_TYPES
+            .iter()
+            .find(|(image_type, _)| content_type.starts_with(image_type))
+        else {
+            return Err(OrchidError::UpstreamError(format!(
+                "{} isn't an image we accept: {}",
+                url, content_type
+            )));
+        };
+        if response
+            .content_length()
+            .is_some_and(|len| len as usize > self.max_bytes)
+        {
+            return Err(self.too_large(url));
+        }
+
+        let mut bytes = vec![];
+        while let Some(chunk) = response.chunk().await.map_err(upstream)? {
+            if bytes.len() + chunk.len() > self.max_bytes {
+                return Err(self.too_large(url));
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+
+        // Write to a temporary file first, so a partly written image is never served
+        tokio::fs::create_dir_all(dir).await?;
+        let path = dir.join(format!("{}.{}", name, extension));
+        let partial = dir.join(format!("{}.partial", name));
+        tokio::fs::write(&partial, &bytes).await?;
+        tokio::fs::rename(&partial, &path).await?;
+        debug!("Cached emote image {}", url);
+
+        Ok(EmoteImage {
+            content_type,
+            bytes,
+        })
+    }
+
+    fn too_large(&self, url: &str) -> OrchidError {
+        OrchidError::UpstreamError(format!("{} is over {} bytes", url, self.max_bytes))
+    }
+}
+
+/// Reads a stored image, if there is one
+async fn read_image(dir: &Path, name: &str) -> OrchidResult<Option<EmoteImage>> {
+    for &(content_type, extension) in IMAGE_TYPES {
+        match tokio::fs::read(dir.join(format!("{}.{}", name, extension))).await {
+            Ok(bytes) => {
+                return Ok(Some(EmoteImage {
+                    content_type,
+                    bytes,
+                }))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(None)
+}
+
+/// Whether a provider serves animated images at their own URL.
+/// The others serve the animated image in place of the static one.
+fn has_separate_animated(provider: EmoteProvider) -> bool {
+    provider == EmoteProvider::Ffz
+}
+
+/// Whether a provider has images at a scale
+fn has_scale(provider: EmoteProvider, scale: u8) -> bool {
+    match provider {
+        EmoteProvider::Twitch | EmoteProvider::Bttv => (1..=3).contains(&scale),
+        EmoteProvider::SevenTv => (1..=4).contains(&scale),
+        EmoteProvider::Ffz => matches!(scale, 1 | 2 | 4),
+    }
+}
+
+/// The local URL serving an emote's image at a scale, if it's one we can proxy.
+/// `source` is the emote's source, e.g. `7TV`. `animated` is whether the image to serve is animated.
+pub fn local_url(source: &str, id: &str, scale: u8, animated: bool) -> Option<String> {
+    let provider: EmoteProvider = source.parse().ok()?;
+    let animated = animated && has_separate_animated(provider);
+    if !has_scale(provider, scale) {
+        return None;
+    }
+    if animated {
+        Some(format!("/emotes/{}/{}/{}/animated", provider, id, scale))
+    } else {
+        Some(format!("/emotes/{}/{}/{}", provider, id, scale))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_urls_use_the_scale() {
+        assert_eq!(
+            local_url("7TV", "abc", 4, false).as_deref(),
+            Some("/emotes/7tv/abc/4")
+        );
+        assert_eq!(
+            local_url("twitch", "25", 1, false).as_deref(),
+            Some("/emotes/twitch/25/1")
+        );
+        assert_eq!(
+            local_url("BetterTTV", "xyz", 3, false).as_deref(),
+            Some("/emotes/bttv/xyz/3")
+        );
+    }
+
+    #[test]
+    fn only_ffz_has_separate_animated_urls() {
+        assert_eq!(
+            local_url("FFZ", "123", 2, true).as_deref(),
+            Some("/emotes/ffz/123/2/animated")
+        );
+        assert_eq!(
+            local_url("FFZ", "123", 2, false).as_deref(),
+            Some("/emotes/ffz/123/2")
+        );
+        assert_eq!(
+            local_url("7TV", "abc", 2, true).as_deref(),
+            Some("/emotes/7tv/abc/2")
+        );
+    }
+
+    #[test]
+    fn rejects_scales_the_provider_lacks() {
+        assert_eq!(local_url("twitch", "25", 4, false), None);
+        assert_eq!(local_url("BTTV", "xyz", 0, false), None);
+        assert_eq!(local_url("7TV", "abc", 5, false), None);
+        assert_eq!(local_url("FFZ", "123", 3, false), None);
+        assert!(local_url("FFZ", "123", 4, false).is_some());
+    }
+
+    #[test]
+    fn rejects_unknown_sources() {
+        assert_eq!(local_url("nope", "25", 1, false), None);
+        assert_eq!(local_url("", "25", 1, false), None);
+    }
+}
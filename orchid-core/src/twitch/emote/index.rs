@@ -0,0 +1,144 @@
+use std::{collections::HashMap, fmt, str::FromStr, sync::Arc};
+
+use super::Emote;
+use crate::err::OrchidError;
+
+/// Emote name -> emote, for one emote set
+pub type EmoteIndex = HashMap<String, Emote>;
+
+/// Indexes emotes by name. If two emotes share a name, the first one wins.
+pub fn index_emotes(emotes: impl IntoIterator<Item = Emote>) -> Arc<EmoteIndex> {
+    let mut index = EmoteIndex::new();
+    for emote in emotes {
+        index.entry(emote.name.clone()).or_insert(emote);
+    }
+    Arc::new(index)
+}
+
+/// Where an emote comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EmoteProvider {
+    Twitch,
+    SevenTv,
+    Bttv,
+    Ffz,
+}
+
+impl fmt::Display for EmoteProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EmoteProvider::Twitch => "twitch",
+            EmoteProvider::SevenTv => "7tv",
+            EmoteProvider::Bttv => "bttv",
+            EmoteProvider::Ffz => "ffz",
+        })
+    }
+}
+
+impl FromStr for EmoteProvider {
+    type Err = OrchidError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "twitch" => Ok(EmoteProvider::Twitch),
+            "7tv" | "seventv" => Ok(EmoteProvider::SevenTv),
+            "bttv" | "betterttv" => Ok(EmoteProvider::Bttv),
+            "ffz" | "frankerfacez" => Ok(EmoteProvider::Ffz),
+            _ => Err(OrchidError::ConfigError(format!(
+                "Unknown emote provider: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Which of a provider's emote sets an emote comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EmoteTier {
+    /// Available everywhere
+    Global,
+    /// Available in the channel the message was sent in
+    Channel,
+    /// Available to the user who sent the message
+    User,
+}
+
+impl fmt::Display for EmoteTier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EmoteTier::Global => "global",
+            EmoteTier::Channel => "channel",
+            EmoteTier::User => "user",
+        })
+    }
+}
+
+impl FromStr for EmoteTier {
+    type Err = OrchidError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "global" => Ok(EmoteTier::Global),
+            "channel" => Ok(EmoteTier::Channel),
+            "user" => Ok(EmoteTier::User),
+            _ => Err(OrchidError::ConfigError(format!(
+                "Unknown emote tier: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// An entry in the emote precedence list, written `provider:tier`, e.g. `7tv:channel`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EmoteSource {
+    pub provider: EmoteProvider,
+    pub tier: EmoteTier,
+}
+
+impl FromStr for EmoteSource {
+    type Err = OrchidError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (provider, tier) = s.trim().split_once(':').ok_or_else(|| {
+            OrchidError::ConfigError(format!("Emote source should be provider:tier, got {}", s))
+        })?;
+        Ok(Self {
+            provider: provider.parse()?,
+            tier: tier.parse()?,
+        })
+    }
+}
+
+/// Default emote precedence: user, channel and global sets, preferring 7TV, then BTTV, then FFZ within each.
+/// Twitch's own emotes come tagged on messages rather than from a manager, so they have no sets here.
+pub fn default_precedence() -> Vec<EmoteSource> {
+    use EmoteProvider::*;
+    use EmoteTier::*;
+
+    let mut precedence = vec![];
+    for tier in [User, Channel, Global] {
+        for provider in [SevenTv, Bttv, Ffz] {
+            precedence.push(EmoteSource { provider, tier });
+        }
+    }
+    precedence
+}
+
+/// The emote sets a provider has for a scope, by tier
+#[derive(Debug, Clone, Default)]
+pub struct ScopedEmotes {
+    pub global: Vec<Arc<EmoteIndex>>,
+    pub channel: Vec<Arc<EmoteIndex>>,
+    pub user: Vec<Arc<EmoteIndex>>,
+}
+
+impl ScopedEmotes {
+    pub fn tier(&self, tier: EmoteTier) -> &[Arc<EmoteIndex>] {
+        match tier {
+            EmoteTier::Global => &self.global,
+            EmoteTier::Channel => &self.channel,
+            EmoteTier::User => &self.user,
+        }
+    }
+}
@@ -1,10 +1,38 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
 
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc::UnboundedSender, Semaphore};
 
+use crate::{
+    err::{OrchidError, OrchidResult},
+    twitch::chat::message::MessageEmote,
+};
+
+pub mod bttv;
+pub mod cache;
+pub mod effects;
 pub mod ffz;
 pub mod firstparty;
+pub mod images;
+pub mod index;
+pub mod seventv;
+pub mod store;
+
+use effects::EmoteEffect;
+use images::EmoteImages;
+use index::{default_precedence, EmoteIndex, EmoteProvider, EmoteSource, ScopedEmotes};
+
+/// `Emote::effect` flag for zero-width emotes, drawn over the previous emote (7TV and BTTV).
+/// Kept clear of the FFZ modifier flags, which are passed through as is.
+pub const ZERO_WIDTH_EFFECT: i64 = 1 << 24;
+
+/// Most emote refreshes run in the background at once.
+/// Every new chatter needs one, so refreshes for messages are dropped past this, and tried again on a later message.
+const MAX_BACKGROUND_REFRESHES: usize = 8;
 
 /// Represents an Twitch emote
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -14,46 +42,332 @@ pub struct Emote {
     pub name: String,
     /// The channel the emote is associated with
     pub channel: String,
+    /// Effect flags: FFZ's modifier flags, or `ZERO_WIDTH_EFFECT`
     pub effect: Option<i64>,
+    /// The default image at each scale, smallest first
     pub url: Vec<String>,
+    /// Every image the provider has for the emote, by scale and format, with sizes
+    #[serde(default)]
+    pub images: EmoteImages,
+    /// `effect`, decoded
+    #[serde(default)]
+    pub effects: Vec<EmoteEffect>,
+    /// Whether the emote is drawn over or transforms the emote before it, rather than standing alone
+    #[serde(default)]
+    pub modifier: bool,
+}
+
+/// Who sent a message and where, for looking up the emotes available to it
+#[derive(Debug, Clone, Default, Hash, PartialEq, Eq)]
+pub struct EmoteScope {
+    pub user_id: String,
+    pub user_name: String,
+    /// The channel ID the message originated in
+    pub channel_id: String,
+    /// The channel name the message originated in.
+    /// May be empty for Shared Chat messages from a channel we haven't joined.
+    pub channel_name: String,
+}
+
+impl EmoteScope {
+    /// Key to cache channel emotes under, for providers that look channels up by name.
+    /// Prefers the channel name, so emotes prefetched when a channel is joined (before its ID is known) are used.
+    pub fn channel_key(&self) -> &str {
+        if self.channel_name.is_empty() {
+            &self.channel_id
+        } else {
+            &self.channel_name
+        }
+    }
 }
 
 #[async_trait]
 pub trait EmoteManager: Send + Sync {
-    async fn fetch(&mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
-    async fn get_emote(&mut self, user_name: &str, channel_name: &str, id: &str) -> Option<Emote>;
+    fn provider(&self) -> EmoteProvider;
+    /// Gets the cached, name-indexed emote sets available to a scope. Never fetches, so it's quick.
+    async fn get_emotes(&self, scope: &EmoteScope) -> ScopedEmotes;
+    /// Expires a scope's channel sets, or the global sets if the scope has no channel, so they're fetched again
+    async fn expire(&self, scope: &EmoteScope);
+    /// Whether any of a scope's emote sets haven't been fetched yet, or have expired
+    async fn needs_refresh(&self, scope: &EmoteScope) -> bool;
+    /// Fetches a scope's missing or expired emote sets. Sets already being fetched are skipped.
+    async fn refresh(&self, scope: &EmoteScope);
+}
+
+/// Replaces emote markup produced by `replace_emotes` with the emote names, e.g. for indexing message text.
+/// Stacked emotes get back the space between them.
+pub fn emote_markup_to_names(message: &str) -> String {
+    let mut result = String::with_capacity(message.len());
+    let mut rest = message;
+    while let Some(start) = rest.find("<!") {
+        let Some(len) = rest[start..].find('>') else {
+            break;
+        };
+        result.push_str(&rest[..start]);
+        let markup = &rest[start + 2..start + len];
+        // The name is the last field. URLs contain colons too, so fall back to the ID if there's no name.
+        match markup.rsplit_once(':') {
+            Some((_, name)) if !name.contains('/') => result.push_str(name),
+            _ => result.push_str(markup.split(':').next().unwrap_or_default()),
+        }
+        rest = &rest[start + len + 1..];
+        if rest.starts_with("<!") {
+            result.push(' ');
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// A channel's emotes from a provider changed after they were fetched again
+#[derive(Debug, Clone)]
+pub struct EmoteSetChange {
+    /// The channel name
+    pub channel: String,
+    pub provider: EmoteProvider,
+}
+
+/// What reloading emotes did
+#[derive(Debug, Clone, Serialize)]
+pub struct EmoteReload {
+    /// Providers whose emotes were fetched again
+    pub reloaded: Vec<String>,
+    /// Providers whose channel emotes changed
+    pub changed: Vec<String>,
 }
 
 pub struct EmoteHandler {
-    pub managers: Vec<Box<dyn EmoteManager>>,
+    pub managers: Vec<Arc<dyn EmoteManager>>,
+    /// Which emote sets win when several have an emote with the same name, highest first.
+    /// Sets not listed are never used.
+    precedence: Vec<EmoteSource>,
+    /// Channel name -> ID, learned from messages, so channels can be reloaded by name
+    channel_ids: Mutex<HashMap<String, String>>,
+    /// Where to report channels whose emotes changed
+    changes: Option<UnboundedSender<EmoteSetChange>>,
+    /// Only serve emotes that are already cached, never fetching
+    offline: bool,
+    /// Point emote markup at the local image proxy rather than the providers' CDNs
+    proxy_images: bool,
+    /// Permits for background refreshes
+    refreshes: Arc<Semaphore>,
 }
 
 impl EmoteHandler {
     pub fn new() -> Self {
-        Self { managers: vec![] }
+        Self {
+            managers: vec![],
+            precedence: default_precedence(),
+            channel_ids: Mutex::new(HashMap::new()),
+            changes: None,
+            offline: false,
+            proxy_images: false,
+            refreshes: Arc::new(Semaphore::new(MAX_BACKGROUND_REFRESHES)),
+        }
     }
-    pub fn add_manager(&mut self, manager: Box<dyn EmoteManager>) {
+    pub fn add_manager(&mut self, manager: Arc<dyn EmoteManager>) {
         self.managers.push(manager);
     }
+    pub fn set_precedence(&mut self, precedence: Vec<EmoteSource>) {
+        self.precedence = precedence;
+    }
+    /// Sets where to report channels whose emotes changed
+    pub fn set_change_notifier(&mut self, changes: UnboundedSender<EmoteSetChange>) {
+        self.changes = Some(changes);
+    }
+    /// Only serve emotes that are already cached, e.g. those loaded from the emote store, never fetching
+    pub fn set_offline(&mut self, offline: bool) {
+        self.offline = offline;
+    }
+    /// Point emote markup at the local image proxy, `/emotes/:source/:id/:scale`
+    pub fn set_image_proxy(&mut self, proxy_images: bool) {
+        self.proxy_images = proxy_images;
+    }
+
+    /// Starts fetching a scope's emote sets in the background, e.g. when a channel is joined.
+    /// Waits its turn behind other background refreshes rather than being dropped.
+    pub fn prefetch(&self, scope: &EmoteScope) {
+        if self.offline {
+            return;
+        }
+        self.remember_channel(scope);
+        for manager in self.managers.iter() {
+            let manager = manager.clone();
+            let scope = scope.clone();
+            let changes = self.changes.clone();
+            let refreshes = self.refreshes.clone();
+            tokio::spawn(async move {
+                let Ok(_permit) = refreshes.acquire_owned().await else {
+                    return;
+                };
+                refresh_and_notify(manager.as_ref(), &scope, changes.as_ref()).await;
+            });
+        }
+    }
+
+    /// Fetches a scope's emote sets in the background, unless too many refreshes are running already
+    fn spawn_refresh(&self, manager: &Arc<dyn EmoteManager>, scope: &EmoteScope) {
+        if self.offline {
+            return;
+        }
+        let Ok(permit) = self.refreshes.clone().try_acquire_owned() else {
+            return;
+        };
+        let manager = manager.clone();
+        let scope = scope.clone();
+        let changes = self.changes.clone();
+        tokio::spawn(async move {
+            refresh_and_notify(manager.as_ref(), &scope, changes.as_ref()).await;
+            drop(permit);
+        });
+    }
 
-    // Get emote from managers
-    async fn get_emote(&mut self, user_name: &str, channel_name: &str, id: &str) -> Option<Emote> {
-        for manager in self.managers.iter_mut() {
-            if let Some(emote) = manager.get_emote(user_name, channel_name, id).await {
-                return Some(emote);
+    /// Remembers a scope's channel ID, so the channel can be reloaded by name
+    fn remember_channel(&self, scope: &EmoteScope) {
+        if !scope.channel_name.is_empty() && !scope.channel_id.is_empty() {
+            self.channel_ids
+                .lock()
+                .unwrap()
+                .insert(scope.channel_name.clone(), scope.channel_id.clone());
+        }
+    }
+
+    /// A scope for a channel name, with the channel's ID if we've seen it in a message
+    pub fn channel_scope(&self, channel: &str) -> EmoteScope {
+        EmoteScope {
+            channel_name: channel.to_string(),
+            channel_id: self
+                .channel_ids
+                .lock()
+                .unwrap()
+                .get(channel)
+                .cloned()
+                .unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+
+    /// Fetches a channel's emotes again now, or the global emotes if no channel is given.
+    /// Fails when offline, or for a channel whose ID we haven't learned yet, as most providers look channels up by ID.
+    pub async fn reload(&self, channel: Option<&str>) -> OrchidResult<EmoteReload> {
+        if self.offline {
+            return Err(OrchidError::Conflict(
+                "Emotes are offline, so they can't be fetched again".to_string(),
+            ));
+        }
+        let scope = match channel {
+            Some(channel) => self.channel_scope(channel),
+            None => EmoteScope::default(),
+        };
+        if let Some(channel) = channel.filter(|_| scope.channel_id.is_empty()) {
+            return Err(OrchidError::NotFound(format!(
+                "the channel ID of {}, which is learned once the channel is joined",
+                channel
+            )));
+        }
+
+        let mut reload = EmoteReload {
+            reloaded: vec![],
+            changed: vec![],
+        };
+        for manager in self.managers.iter() {
+            manager.expire(&scope).await;
+            if refresh_and_notify(manager.as_ref(), &scope, self.changes.as_ref()).await {
+                reload.changed.push(manager.provider().to_string());
             }
+            reload.reloaded.push(manager.provider().to_string());
         }
-        None
+        Ok(reload)
+    }
+
+    /// Gets the cached emote sets available to a scope, in order of precedence.
+    /// Sets that are missing or expired are fetched in the background for later messages.
+    async fn get_emote_sets(&self, scope: &EmoteScope) -> Vec<Arc<EmoteIndex>> {
+        self.remember_channel(scope);
+
+        let mut scoped = HashMap::new();
+        for manager in self.managers.iter() {
+            if manager.needs_refresh(scope).await {
+                self.spawn_refresh(manager, scope);
+            }
+            scoped.insert(manager.provider(), manager.get_emotes(scope).await);
+        }
+        self.precedence
+            .iter()
+            .filter_map(|source| Some(scoped.get(&source.provider)?.tier(source.tier)))
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    /// Every emote usable in a scope, by name, with the one that wins when several share a name.
+    /// Missing or expired sets are fetched in the background, as for messages.
+    pub async fn catalog(&self, scope: &EmoteScope) -> Vec<Emote> {
+        let sets = self.get_emote_sets(scope).await;
+        let mut emotes: Vec<&Emote> = winning_emotes(&sets, |_| true);
+        emotes.sort_by(|a, b| by_name(a, b));
+        emotes
+            .into_iter()
+            .map(|emote| self.proxied(emote))
+            .collect()
+    }
+
+    /// Emotes in a scope whose names start with a prefix, ignoring case, for autocomplete.
+    /// Shorter names come first, so the closest matches are at the top.
+    pub async fn search(&self, scope: &EmoteScope, prefix: &str, limit: usize) -> Vec<Emote> {
+        let sets = self.get_emote_sets(scope).await;
+        let prefix = prefix.to_lowercase();
+        let mut emotes = winning_emotes(&sets, |name| name.to_lowercase().starts_with(&prefix));
+        let order =
+            |a: &&Emote, b: &&Emote| a.name.len().cmp(&b.name.len()).then_with(|| by_name(a, b));
+        // Only the top matches need sorting
+        if emotes.len() > limit {
+            emotes.select_nth_unstable_by(limit, order);
+            emotes.truncate(limit);
+        }
+        emotes.sort_by(order);
+        emotes
+            .into_iter()
+            .map(|emote| self.proxied(emote))
+            .collect()
+    }
+
+    /// An emote with its default images pointing at the local image proxy, if it's enabled.
+    /// Other formats keep their upstream URLs.
+    fn proxied(&self, emote: &Emote) -> Emote {
+        let mut emote = emote.clone();
+        if !self.proxy_images || emote.images.scales.is_empty() {
+            return emote;
+        }
+        for scale in emote.images.scales.iter_mut() {
+            let animated = scale
+                .formats
+                .iter()
+                .any(|format| format.animated && format.url == scale.url);
+            if let Some(url) = images::local_url(&emote.source, &emote.id, scale.scale, animated) {
+                scale.url = url;
+            }
+        }
+        emote.url = emote.images.urls();
+        emote
     }
 
     // Replace detected emote names with image tags
-    // <!:id:url:effect:overlay>
-    pub async fn replace_emotes(&self, message: &str, emotes: &HashMap<String, Emote>) -> String {
-        let mut new_message = message.to_string();
+    // <!id:url:effect:name>
+    // Only whole words are replaced, so emote names inside other words, URLs or markup are left alone.
+    // Modifiers stacked on the emote before them follow its markup directly, without the space between,
+    // e.g. `<!base:...><!modifier:...>`. `stacked` holds the indexes of those words.
+    pub async fn replace_emotes(
+        &self,
+        message: &str,
+        emotes: &HashMap<String, Emote>,
+        stacked: &HashSet<usize>,
+    ) -> String {
+        let mut new_message = String::with_capacity(message.len());
         let replacement = |emote: &Emote| {
             let mut result = format!("<!{}", emote.id);
             if !emote.url.is_empty() {
-                result.push_str(&format!(":{}", emote.url.join(",")));
+                result.push_str(&format!(":{}", self.proxied(emote).url.join(",")));
             }
             if let Some(effect) = emote.effect {
                 result.push_str(&format!(":{}", effect));
@@ -64,33 +378,172 @@ impl EmoteHandler {
             result.push('>');
             result
         };
-        for emote in emotes {
-            new_message = new_message.replace(emote.0, &replacement(emote.1));
+        // Leading whitespace doesn't start a word
+        let space_end = message
+            .find(|c: char| !c.is_whitespace())
+            .unwrap_or(message.len());
+        new_message.push_str(&message[..space_end]);
+        let mut rest = &message[space_end..];
+        let mut word_index = 0;
+        while !rest.is_empty() {
+            let word_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let (word, after) = rest.split_at(word_end);
+            match emotes.get(word) {
+                Some(emote) => new_message.push_str(&replacement(emote)),
+                None => new_message.push_str(word),
+            }
+            // Keep the whitespace between words as it was, unless the next word is stacked on this one
+            let space_end = after
+                .find(|c: char| !c.is_whitespace())
+                .unwrap_or(after.len());
+            word_index += 1;
+            if !stacked.contains(&word_index) || space_end == after.len() {
+                new_message.push_str(&after[..space_end]);
+            }
+            rest = &after[space_end..];
         }
         new_message
     }
     /// Process a message with emotes, using the emote managers provided.
+    /// Returns the message with emote markup, and the emotes used, with modifier emotes
+    /// stacked on the emote before them. A modifier with no emote before it stands alone.
+    /// Channel emotes are looked up for the channel the message originated in,
+    /// which differs from the joined channel for Shared Chat messages.
     pub async fn process_message_with_emotes(
-        &mut self,
+        &self,
         message: &str,
-        user_name: &str,
-        channel_name: &str,
-    ) -> String {
+        scope: &EmoteScope,
+    ) -> (String, Vec<MessageEmote>) {
+        let sets = self.get_emote_sets(scope).await;
         let mut found_emotes = HashMap::new();
-        for manager in self.managers.iter_mut() {
-            // have to process each word :(
-            for word in message.split_whitespace() {
-                if let Some(emote) = manager.get_emote(user_name, channel_name, word).await {
-                    found_emotes.insert(word.to_string(), emote);
+        let mut used: Vec<MessageEmote> = vec![];
+        let mut stacked = HashSet::new();
+        for (word_index, word) in message.split_whitespace().enumerate() {
+            let Some(emote) = sets.iter().find_map(|set| set.get(word)) else {
+                continue;
+            };
+            if !found_emotes.contains_key(word) {
+                found_emotes.insert(word.to_string(), emote.clone());
+            }
+
+            let emote = self.proxied(emote);
+            match used.last_mut() {
+                // Only stack on the word right before, or right after its other modifiers
+                Some(base)
+                    if emote.modifier && base.word + base.modifiers.len() + 1 == word_index =>
+                {
+                    base.modifiers.push(emote);
+                    stacked.insert(word_index);
                 }
+                _ => used.push(MessageEmote {
+                    word: word_index,
+                    emote,
+                    modifiers: vec![],
+                }),
             }
         }
-        self.replace_emotes(message, &found_emotes).await
+        (
+            self.replace_emotes(message, &found_emotes, &stacked).await,
+            used,
+        )
     }
 }
 
+/// The emotes in sets whose names match, taking the first set's emote when several share a name
+fn winning_emotes<'a>(
+    sets: &'a [Arc<EmoteIndex>],
+    matches: impl Fn(&str) -> bool,
+) -> Vec<&'a Emote> {
+    let mut emotes: HashMap<&str, &Emote> = HashMap::new();
+    for set in sets.iter() {
+        for (name, emote) in set.iter() {
+            if !emotes.contains_key(name.as_str()) && matches(name) {
+                emotes.insert(name, emote);
+            }
+        }
+    }
+    emotes.into_values().collect()
+}
+
+/// Orders emotes by name, ignoring case first
+fn by_name(a: &Emote, b: &Emote) -> std::cmp::Ordering {
+    a.name
+        .to_lowercase()
+        .cmp(&b.name.to_lowercase())
+        .then_with(|| a.name.cmp(&b.name))
+}
+
+/// Refreshes a scope's emote sets, reporting the channel if its emotes changed
+async fn refresh_and_notify(
+    manager: &dyn EmoteManager,
+    scope: &EmoteScope,
+    changes: Option<&UnboundedSender<EmoteSetChange>>,
+) -> bool {
+    let before = channel_fingerprint(&manager.get_emotes(scope).await);
+    manager.refresh(scope).await;
+    let after = channel_fingerprint(&manager.get_emotes(scope).await);
+
+    let changed = before != after && !scope.channel_name.is_empty();
+    if changed {
+        if let Some(changes) = changes {
+            let _ = changes.send(EmoteSetChange {
+                channel: scope.channel_name.clone(),
+                provider: manager.provider(),
+            });
+        }
+    }
+    changed
+}
+
+/// The (name, ID) pairs of a scope's channel emotes, for noticing changes
+fn channel_fingerprint(emotes: &ScopedEmotes) -> BTreeSet<(String, String)> {
+    emotes
+        .channel
+        .iter()
+        .flat_map(|set| set.values())
+        .map(|emote| (emote.name.clone(), emote.id.clone()))
+        .collect()
+}
+
 impl Default for EmoteHandler {
     fn default() -> Self {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markup_becomes_names() {
+        assert_eq!(
+            emote_markup_to_names("hi <!25:https://cdn/25/1.0,https://cdn/25/2.0:Kappa> there"),
+            "hi Kappa there"
+        );
+        assert_eq!(emote_markup_to_names("<!25:Kappa>"), "Kappa");
+    }
+
+    #[test]
+    fn markup_without_a_name_becomes_the_id() {
+        assert_eq!(emote_markup_to_names("<!25:https://cdn/25/1.0>!"), "25!");
+        assert_eq!(emote_markup_to_names("<!25>"), "25");
+    }
+
+    #[test]
+    fn stacked_markup_gets_its_space_back() {
+        assert_eq!(
+            emote_markup_to_names("a <!1:Base><!2:16777216:Overlay><!3:2:Flip> b"),
+            "a Base Overlay Flip b"
+        );
+    }
+
+    #[test]
+    fn text_without_markup_is_unchanged() {
+        assert_eq!(
+            emote_markup_to_names("no emotes <! here"),
+            "no emotes <! here"
+        );
+        assert_eq!(emote_markup_to_names(""), "");
+    }
+}
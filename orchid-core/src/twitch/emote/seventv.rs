@@ -0,0 +1,644 @@
+use async_trait::async_trait;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// Gets 7TV emotes
+use crate::db::EmoteCacheEntry;
+use crate::twitch::emote::cache::{EmoteTtls, Fetched, TtlCache};
+use crate::twitch::emote::effects::EmoteEffect;
+use crate::twitch::emote::images::{EmoteImageFormat, EmoteImageScale, EmoteImages};
+use crate::twitch::emote::index::{EmoteIndex, EmoteProvider, EmoteTier, ScopedEmotes};
+use crate::twitch::emote::store::{read_json, CachedEmoteSet, EmoteStore, FetchedSets};
+use crate::twitch::emote::Emote;
+use crate::twitch::emote::EmoteManager;
+use crate::twitch::emote::EmoteScope;
+use crate::twitch::emote::ZERO_WIDTH_EFFECT;
+use reqwest::Client;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::sync::RwLock;
+use tracing::error;
+
+type FetchResult<T> = Result<Option<T>, Box<dyn std::error::Error + Send + Sync>>;
+
+/// 7TV's public API
+pub const SEVENTV_API_URL: &str = "https://7tv.io/v3";
+/// Where 7TV serves emote images
+pub const SEVENTV_CDN_URL: &str = "https://cdn.7tv.app/emote";
+
+/// Cache key for the global emote set
+const GLOBAL: &str = "@global";
+/// Emote set flag marking a user's personal set
+const PERSONAL_SET_FLAG: i64 = 1 << 2;
+/// Most users whose personal sets are kept. Every chatter is looked up, so the ones expiring soonest make room.
+const MAX_CACHED_USERS: usize = 10_000;
+
+pub struct SevenTvEmoteManager {
+    /// Emote caches. Only locked while reading or updating, never during requests.
+    cache: RwLock<SevenTvCache>,
+    client: Client,
+    base_url: String,
+    ttls: EmoteTtls,
+    /// Where fetched sets are kept across restarts
+    store: Option<EmoteStore>,
+}
+
+#[derive(Default)]
+struct SevenTvCache {
+    /// Set ID -> emotes by name
+    sets: HashMap<String, Arc<EmoteIndex>>,
+    /// The global set
+    global: TtlCache<String>,
+    /// Channel ID -> active set
+    channels: TtlCache<String>,
+    /// User ID -> personal sets
+    user_sets: TtlCache<Vec<String>>,
+}
+
+impl SevenTvCache {
+    /// Caches a set, returning its ID
+    fn cache_set(&mut self, set: &CachedEmoteSet) -> String {
+        self.sets.insert(set.id.clone(), set.index());
+        set.id.clone()
+    }
+
+    /// Restores a fetch from the emote store
+    fn restore(&mut self, entry: EmoteCacheEntry, ttls: &EmoteTtls) {
+        let Ok(tier) = entry.tier.parse::<EmoteTier>() else {
+            return;
+        };
+        let ids: Option<Vec<String>> =
+            (!entry.missing).then(|| entry.sets.iter().map(|set| self.cache_set(set)).collect());
+        let age = entry.age();
+        match tier {
+            EmoteTier::Global => {
+                let id = ids.and_then(|ids| ids.into_iter().next());
+                self.global.restore(&entry.scope_key, id, age, ttls);
+            }
+            EmoteTier::Channel => {
+                let id = ids.and_then(|ids| ids.into_iter().next());
+                self.channels.restore(&entry.scope_key, id, age, ttls);
+            }
+            EmoteTier::User => {
+                self.user_sets.restore(&entry.scope_key, ids, age, ttls);
+                self.evict_users();
+            }
+        }
+    }
+
+    /// A cached set's emotes, for storing
+    fn stored_set(&self, id: &str) -> Option<CachedEmoteSet> {
+        Some(CachedEmoteSet {
+            id: id.to_string(),
+            emotes: self.sets.get(id)?.values().cloned().collect(),
+        })
+    }
+
+    /// Makes room for more users once there are too many, dropping personal sets nobody else has
+    fn evict_users(&mut self) {
+        // Evict a tenth at a time, so this doesn't run for every new user
+        let evicted = self
+            .user_sets
+            .evict(MAX_CACHED_USERS, MAX_CACHED_USERS * 9 / 10);
+        if evicted.is_empty() {
+            return;
+        }
+        let in_use: HashSet<&String> = self
+            .global
+            .values()
+            .chain(self.channels.values())
+            .chain(self.user_sets.values().flatten())
+            .collect();
+        let unused: Vec<String> = evicted
+            .into_iter()
+            .flatten()
+            .filter(|id| !in_use.contains(id))
+            .collect();
+        for id in unused {
+            self.sets.remove(&id);
+        }
+    }
+
+    fn resolve<'a>(&self, set_ids: impl IntoIterator<Item = &'a String>) -> Vec<Arc<EmoteIndex>> {
+        set_ids
+            .into_iter()
+            .filter_map(|id| self.sets.get(id).cloned())
+            .collect()
+    }
+}
+
+impl SevenTvEmoteManager {
+    /// Creates a manager using the given API base URL, e.g. `SEVENTV_API_URL`
+    pub fn new(base_url: &str, ttls: EmoteTtls) -> Self {
+        Self {
+            client: Client::new(),
+            cache: RwLock::new(SevenTvCache::default()),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            ttls,
+            store: None,
+        }
+    }
+
+    /// Loads sets fetched before from the store, and keeps sets fetched from now on in it
+    pub fn with_store(mut self, store: EmoteStore) -> Self {
+        match store.load(EmoteProvider::SevenTv, &self.ttls) {
+            Ok(entries) => {
+                let cache = self.cache.get_mut();
+                for entry in entries {
+                    cache.restore(entry, &self.ttls);
+                }
+            }
+            Err(e) => error!("Failed to load cached 7TV emotes: {}", e),
+        }
+        self.store = Some(store);
+        self
+    }
+
+    fn persist(&self, tier: EmoteTier, key: &str, fetched: Option<&FetchedSets>) {
+        if let Some(store) = &self.store {
+            store.save(EmoteProvider::SevenTv, tier, key, fetched);
+        }
+    }
+
+    async fn fetch_global_set(&self) -> FetchResult<FetchedSets> {
+        let response = self
+            .client
+            .get(format!("{}/emote-sets/global", self.base_url))
+            .send()
+            .await?;
+        let (set, response) = read_json::<SevenTvEmoteSet>(response).await?;
+        Ok(Some(FetchedSets {
+            sets: vec![set.into_cached("global")],
+            response,
+        }))
+    }
+
+    /// Fetches a channel's active set
+    async fn fetch_channel_set(&self, channel_id: &str) -> FetchResult<FetchedSets> {
+        let Some((data, response)) = self.fetch_connection(channel_id).await? else {
+            return Ok(None);
+        };
+        Ok(data.emote_set.map(|set| FetchedSets {
+            sets: vec![set.into_cached(channel_id)],
+            response,
+        }))
+    }
+
+    async fn fetch_connection(
+        &self,
+        twitch_id: &str,
+    ) -> FetchResult<(SevenTvConnectionResponse, String)> {
+        let response = self
+            .client
+            .get(format!("{}/users/twitch/{}", self.base_url, twitch_id))
+            .send()
+            .await?;
+
+        // Users and channels without a 7TV account have no emotes
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        Ok(Some(read_json(response).await?))
+    }
+
+    /// Fetches a user's personal sets, returning their IDs, the sets we didn't have yet and the connection response.
+    /// Personal sets aren't included in the connection, so the ones we don't have yet are fetched separately.
+    async fn fetch_user_sets(
+        &self,
+        user_id: &str,
+    ) -> FetchResult<(Vec<String>, Vec<CachedEmoteSet>, String)> {
+        let Some((data, response)) = self.fetch_connection(user_id).await? else {
+            return Ok(None);
+        };
+        let personal: Vec<String> = data
+            .user
+            .map(|user| user.emote_sets)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|set| set.flags & PERSONAL_SET_FLAG != 0)
+            .map(|set| set.id)
+            .collect();
+
+        let mut fetched = vec![];
+        for set_id in &personal {
+            if self.cache.read().await.sets.contains_key(set_id) {
+                continue;
+            }
+            let set = self
+                .client
+                .get(format!("{}/emote-sets/{}", self.base_url, set_id))
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<SevenTvEmoteSet>()
+                .await?;
+            fetched.push(set.into_cached(""));
+        }
+        Ok(Some((personal, fetched, response)))
+    }
+
+    async fn store_global(&self, result: FetchResult<FetchedSets>) {
+        if let Ok(fetched) = &result {
+            self.persist(EmoteTier::Global, GLOBAL, fetched.as_ref());
+        }
+        let mut cache = self.cache.write().await;
+        let result = result.map(|fetched| {
+            fetched.and_then(|fetched| Some(cache.cache_set(fetched.sets.first()?)))
+        });
+        let fetched = Fetched::from_result(result, "7TV global emotes");
+        cache.global.finish(GLOBAL, fetched, &self.ttls);
+    }
+}
+
+impl Default for SevenTvEmoteManager {
+    fn default() -> Self {
+        Self::new(SEVENTV_API_URL, EmoteTtls::default())
+    }
+}
+
+#[async_trait]
+impl EmoteManager for SevenTvEmoteManager {
+    fn provider(&self) -> EmoteProvider {
+        EmoteProvider::SevenTv
+    }
+
+    /// Gets the cached global, channel and personal sets. Never fetches.
+    /// Channel sets are keyed by Twitch channel ID.
+    async fn get_emotes(&self, scope: &EmoteScope) -> ScopedEmotes {
+        let cache = self.cache.read().await;
+        ScopedEmotes {
+            global: cache.resolve(cache.global.get(GLOBAL)),
+            channel: cache.resolve(cache.channels.get(&scope.channel_id)),
+            user: cache.resolve(cache.user_sets.get(&scope.user_id).into_iter().flatten()),
+        }
+    }
+
+    async fn expire(&self, scope: &EmoteScope) {
+        let mut cache = self.cache.write().await;
+        if scope.channel_id.is_empty() && scope.channel_name.is_empty() {
+            cache.global.expire(GLOBAL);
+        } else if !scope.channel_id.is_empty() {
+            cache.channels.expire(&scope.channel_id);
+        }
+    }
+
+    async fn needs_refresh(&self, scope: &EmoteScope) -> bool {
+        let cache = self.cache.read().await;
+        cache.global.needs_fetch(GLOBAL)
+            || (!scope.channel_id.is_empty() && cache.channels.needs_fetch(&scope.channel_id))
+            || (!scope.user_id.is_empty() && cache.user_sets.needs_fetch(&scope.user_id))
+    }
+
+    /// Fetches the global, channel and personal sets that are missing or expired.
+    /// The cache lock is released while fetching, so lookups aren't held up.
+    async fn refresh(&self, scope: &EmoteScope) {
+        let channel_id = scope.channel_id.as_str();
+        let user_id = scope.user_id.as_str();
+        let (global, channel, user) = {
+            let mut cache = self.cache.write().await;
+            (
+                cache.global.claim(GLOBAL),
+                !channel_id.is_empty() && cache.channels.claim(channel_id),
+                !user_id.is_empty() && cache.user_sets.claim(user_id),
+            )
+        };
+
+        if global {
+            let result = self.fetch_global_set().await;
+            self.store_global(result).await;
+        }
+        if channel {
+            let result = self.fetch_channel_set(channel_id).await;
+            if let Ok(fetched) = &result {
+                self.persist(EmoteTier::Channel, channel_id, fetched.as_ref());
+            }
+            let mut cache = self.cache.write().await;
+            let result = result.map(|fetched| {
+                fetched.and_then(|fetched| Some(cache.cache_set(fetched.sets.first()?)))
+            });
+            let fetched = Fetched::from_result(result, &format!("7TV emotes for {}", channel_id));
+            cache.channels.finish(channel_id, fetched, &self.ttls);
+        }
+        if user {
+            let result = self.fetch_user_sets(user_id).await;
+            let mut cache = self.cache.write().await;
+            let result = result.map(|data| {
+                data.map(|(personal, sets, response)| {
+                    for set in &sets {
+                        cache.cache_set(set);
+                    }
+                    let stored = FetchedSets {
+                        sets: personal
+                            .iter()
+                            .filter_map(|id| cache.stored_set(id))
+                            .collect(),
+                        response,
+                    };
+                    self.persist(EmoteTier::User, user_id, Some(&stored));
+                    personal
+                })
+            });
+            if let Ok(None) = &result {
+                self.persist(EmoteTier::User, user_id, None);
+            }
+            let fetched = Fetched::from_result(result, &format!("7TV emotes for user {}", user_id));
+            cache.user_sets.finish(user_id, fetched, &self.ttls);
+            cache.evict_users();
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SevenTvConnectionResponse {
+    emote_set: Option<SevenTvEmoteSet>,
+    user: Option<SevenTvUser>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SevenTvUser {
+    id: String,
+    #[serde(default)]
+    emote_sets: Vec<SevenTvEmoteSetRef>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SevenTvEmoteSetRef {
+    id: String,
+    #[serde(default)]
+    flags: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SevenTvEmoteSet {
+    id: String,
+    name: String,
+    #[serde(default)]
+    emotes: Vec<SevenTvActiveEmote>,
+}
+
+impl SevenTvEmoteSet {
+    fn into_cached(self, channel: &str) -> CachedEmoteSet {
+        CachedEmoteSet {
+            emotes: self
+                .emotes
+                .into_iter()
+                .map(|emote| emote.into_emote(channel))
+                .collect(),
+            id: self.id,
+        }
+    }
+}
+
+/// An emote as enabled in a set. The name may be an alias.
+#[derive(Debug, Serialize, Deserialize)]
+struct SevenTvActiveEmote {
+    id: String,
+    name: String,
+    /// Set-level flags, e.g. zero-width (1)
+    #[serde(default)]
+    flags: i64,
+    data: SevenTvEmoteData,
+}
+
+impl SevenTvActiveEmote {
+    /// Set-level zero-width flag
+    const ZERO_WIDTH: i64 = 1 << 0;
+
+    fn into_emote(self, channel: &str) -> Emote {
+        let zero_width = self.flags & Self::ZERO_WIDTH != 0
+            || self.data.flags & SevenTvEmoteData::ZERO_WIDTH != 0;
+        let images = self.data.host.images(self.data.animated);
+        Emote {
+            source: "7TV".to_string(),
+            url: images.urls(),
+            images,
+            id: self.id,
+            name: self.name,
+            channel: channel.to_string(),
+            effect: zero_width.then_some(ZERO_WIDTH_EFFECT),
+            effects: zero_width
+                .then_some(EmoteEffect::ZeroWidth)
+                .into_iter()
+                .collect(),
+            modifier: zero_width,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SevenTvEmoteData {
+    id: String,
+    name: String,
+    /// Emote-level flags, e.g. zero-width (256)
+    #[serde(default)]
+    flags: i64,
+    #[serde(default)]
+    animated: bool,
+    host: SevenTvImageHost,
+}
+
+impl SevenTvEmoteData {
+    /// Emote-level zero-width flag
+    const ZERO_WIDTH: i64 = 1 << 8;
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SevenTvImageHost {
+    /// Protocol-relative base URL, e.g. `//cdn.7tv.app/emote/<id>`
+    url: String,
+    files: Vec<SevenTvImageFile>,
+}
+
+impl SevenTvImageHost {
+    /// Images by scale, in every format 7TV has. WebP is the default, animated if the emote is.
+    fn images(&self, animated: bool) -> EmoteImages {
+        let base = if self.url.starts_with("//") {
+            format!("https:{}", self.url)
+        } else {
+            self.url.clone()
+        };
+        let mut scales: Vec<EmoteImageScale> = vec![];
+        for file in &self.files {
+            let Some(scale) = file.scale() else {
+                continue;
+            };
+            let index = match scales.iter().position(|entry| entry.scale == scale) {
+                Some(index) => index,
+                None => {
+                    scales.push(EmoteImageScale {
+                        scale,
+                        url: String::new(),
+                        width: Some(file.width as u32),
+                        height: Some(file.height as u32),
+                        formats: vec![],
+                    });
+                    scales.len() - 1
+                }
+            };
+            let format = file.format.to_lowercase();
+            let formats = &mut scales[index].formats;
+            formats.push(EmoteImageFormat {
+                format: format.clone(),
+                animated,
+                url: format!("{}/{}", base, file.name),
+            });
+            if let (true, Some(static_name)) = (animated, &file.static_name) {
+                formats.push(EmoteImageFormat {
+                    format,
+                    animated: false,
+                    url: format!("{}/{}", base, static_name),
+                });
+            }
+        }
+
+        for entry in scales.iter_mut() {
+            let default = entry
+                .formats
+                .iter()
+                .find(|image| image.format == "webp" && image.animated == animated)
+                .or(entry.formats.first());
+            entry.url = default.map(|image| image.url.clone()).unwrap_or_default();
+        }
+        scales.sort_by_key(|entry| entry.scale);
+        EmoteImages { animated, scales }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SevenTvImageFile {
+    /// File name, e.g. `1x.webp`
+    name: String,
+    /// Name of the static variant, e.g. `1x_static.webp`
+    static_name: Option<String>,
+    width: i32,
+    height: i32,
+    format: String,
+}
+
+impl SevenTvImageFile {
+    /// The scale from the file name, e.g. 2 for `2x.webp`
+    fn scale(&self) -> Option<u8> {
+        let digits: String = self
+            .name
+            .chars()
+            .take_while(|c| c.is_ascii_digit())
+            .collect();
+        digits.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host() -> SevenTvImageHost {
+        let files: Vec<_> = [(4, 128, 112), (1, 32, 28), (2, 64, 56)]
+            .into_iter()
+            .flat_map(|(scale, width, height)| {
+                ["WEBP", "AVIF"].map(|format| {
+                    let extension = format.to_lowercase();
+                    serde_json::json!({
+                        "name": format!("{}x.{}", scale, extension),
+                        "static_name": format!("{}x_static.{}", scale, extension),
+                        "width": width,
+                        "height": height,
+                        "format": format
+                    })
+                })
+            })
+            .collect();
+        serde_json::from_value(serde_json::json!({
+            "url": "//cdn.7tv.app/emote/60ae958e229664e8667aea38",
+            "files": files
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn images_are_sorted_by_scale() {
+        let images = host().images(false);
+        let scales: Vec<_> = images
+            .scales
+            .iter()
+            .map(|scale| (scale.scale, scale.width, scale.height))
+            .collect();
+        assert_eq!(
+            scales,
+            [
+                (1, Some(32), Some(28)),
+                (2, Some(64), Some(56)),
+                (4, Some(128), Some(112))
+            ]
+        );
+    }
+
+    #[test]
+    fn static_emotes_default_to_webp() {
+        let images = host().images(false);
+        assert!(!images.animated);
+        assert_eq!(
+            images.scales[0].url,
+            "https://cdn.7tv.app/emote/60ae958e229664e8667aea38/1x.webp"
+        );
+        // No separate static images when the emote isn't animated
+        assert_eq!(images.scales[0].formats.len(), 2);
+        assert!(images.scales[0]
+            .formats
+            .iter()
+            .all(|format| !format.animated));
+    }
+
+    #[test]
+    fn animated_emotes_default_to_animated_webp() {
+        let images = host().images(true);
+        assert!(images.animated);
+        let scale = &images.scales[1];
+        assert_eq!(
+            scale.url,
+            "https://cdn.7tv.app/emote/60ae958e229664e8667aea38/2x.webp"
+        );
+        assert!(scale.formats.iter().any(|format| format.format == "avif"
+            && !format.animated
+            && format.url.ends_with("/2x_static.avif")));
+        assert_eq!(scale.formats.len(), 4);
+    }
+
+    fn active_emote(set_flags: i64, emote_flags: i64) -> SevenTvActiveEmote {
+        serde_json::from_value(serde_json::json!({
+            "id": "60ae958e229664e8667aea38",
+            "name": "RainTime",
+            "flags": set_flags,
+            "data": {
+                "id": "60ae958e229664e8667aea38",
+                "name": "RainTime",
+                "flags": emote_flags,
+                "host": {"url": "//cdn.7tv.app/emote/60ae958e229664e8667aea38", "files": []}
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn zero_width_emotes_use_their_own_flag() {
+        for (set_flags, emote_flags) in [
+            (SevenTvActiveEmote::ZERO_WIDTH, 0),
+            (0, SevenTvEmoteData::ZERO_WIDTH),
+        ] {
+            let emote = active_emote(set_flags, emote_flags).into_emote("channel");
+            assert_eq!(emote.effect, Some(ZERO_WIDTH_EFFECT));
+            assert_eq!(emote.effects, [EmoteEffect::ZeroWidth]);
+            assert!(emote.modifier);
+        }
+        // Kept clear of FFZ's flags, so it isn't read as FFZ's Hidden
+        assert!(!EmoteEffect::from_flags(ZERO_WIDTH_EFFECT).contains(&EmoteEffect::Hidden));
+    }
+
+    #[test]
+    fn other_emotes_have_no_effect() {
+        let emote = active_emote(0, 0).into_emote("channel");
+        assert_eq!(emote.effect, None);
+        assert!(emote.effects.is_empty());
+        assert!(!emote.modifier);
+    }
+}
@@ -0,0 +1,161 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::Utc;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tracing::{debug, error};
+use turbosql::{execute, select, Turbosql};
+
+use super::{
+    cache::EmoteTtls,
+    index::{index_emotes, EmoteIndex, EmoteProvider, EmoteTier},
+    Emote,
+};
+use crate::{
+    db::{replace_row, transaction, EmoteCacheEntry},
+    err::OrchidResult,
+};
+
+/// An emote set, as kept in the emote cache
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CachedEmoteSet {
+    pub id: String,
+    pub emotes: Vec<Emote>,
+}
+
+impl CachedEmoteSet {
+    /// Indexes the set's emotes by name
+    pub fn index(&self) -> Arc<EmoteIndex> {
+        index_emotes(self.emotes.iter().cloned())
+    }
+}
+
+/// Emote sets fetched from a provider, with the response they came from
+#[derive(Debug, Clone, Default)]
+pub struct FetchedSets {
+    pub sets: Vec<CachedEmoteSet>,
+    /// The raw response body
+    pub response: String,
+}
+
+impl EmoteCacheEntry {
+    /// How long ago this was fetched
+    pub fn age(&self) -> Duration {
+        Duration::from_millis((Utc::now().timestamp_millis() - self.fetched_at).max(0) as u64)
+    }
+
+    /// Whether this was cached before emote images were described by scale
+    fn predates_images(&self) -> bool {
+        self.sets
+            .iter()
+            .flat_map(|set| &set.emotes)
+            .any(|emote| emote.images.scales.is_empty() && !emote.url.is_empty())
+    }
+}
+
+/// Reads a JSON response, keeping the body so it can be cached alongside what's taken from it
+pub async fn read_json<T: DeserializeOwned>(
+    response: reqwest::Response,
+) -> Result<(T, String), Box<dyn std::error::Error + Send + Sync>> {
+    let body = response.error_for_status()?.text().await?;
+    Ok((serde_json::from_str(&body)?, body))
+}
+
+/// Keeps emote fetch results in the database, so emotes are available straight after a restart,
+/// or without a network connection. Results are written on a blocking thread.
+#[derive(Clone)]
+pub struct EmoteStore {
+    tx: UnboundedSender<EmoteCacheEntry>,
+}
+
+impl EmoteStore {
+    /// Starts the database writer
+    pub fn start() -> Self {
+        let (tx, mut rx) = mpsc::unbounded_channel::<EmoteCacheEntry>();
+        tokio::task::spawn_blocking(move || {
+            while let Some(entry) = rx.blocking_recv() {
+                let result = transaction(|| {
+                    replace_row(
+                        || {
+                            execute!(
+                                "DELETE FROM emotecacheentry WHERE provider = ? AND tier = ? AND scope_key = ?",
+                                entry.provider,
+                                entry.tier,
+                                entry.scope_key
+                            )
+                        },
+                        &entry,
+                    )
+                });
+                if let Err(e) = result {
+                    error!("Failed to cache emotes: {}", e);
+                }
+            }
+        });
+        Self { tx }
+    }
+
+    /// Loads a provider's cached fetch results, first dropping ones that would be fetched again anyway:
+    /// "missing" results past the missing TTL, and per-user results past the refresh TTL,
+    /// as there's one of those for every chatter.
+    pub fn load(
+        &self,
+        provider: EmoteProvider,
+        ttls: &EmoteTtls,
+    ) -> OrchidResult<Vec<EmoteCacheEntry>> {
+        let name = provider.to_string();
+        let user = EmoteTier::User.to_string();
+        let now = Utc::now().timestamp_millis();
+        let missing_before = now - ttls.missing.as_millis() as i64;
+        let user_before = now - ttls.refresh.as_millis() as i64;
+        execute!(
+            "DELETE FROM emotecacheentry WHERE provider = ? AND ((missing AND fetched_at < ?) OR (tier = ? AND fetched_at < ?))",
+            name,
+            missing_before,
+            user,
+            user_before
+        )?;
+
+        let mut entries = select!(Vec<EmoteCacheEntry> "WHERE provider = ?", name)?;
+        // Emotes cached before images were described by scale only have their URLs, in no particular order.
+        // Leave those to be fetched again rather than guessing each URL's scale.
+        let before = entries.len();
+        entries.retain(|entry| !entry.predates_images());
+        if entries.len() < before {
+            debug!(
+                "Skipped {} cached {} emote fetches without image descriptions",
+                before - entries.len(),
+                provider
+            );
+        }
+        debug!("Loaded {} cached {} emote fetches", entries.len(), provider);
+        Ok(entries)
+    }
+
+    /// Stores a successful fetch. `None` means the provider doesn't have the key.
+    /// Per-user results only keep their sets: user responses can embed whole channel sets (7TV),
+    /// and there's one for every chatter.
+    pub fn save(
+        &self,
+        provider: EmoteProvider,
+        tier: EmoteTier,
+        key: &str,
+        fetched: Option<&FetchedSets>,
+    ) {
+        let _ = self.tx.send(EmoteCacheEntry {
+            rowid: None,
+            provider: provider.to_string(),
+            tier: tier.to_string(),
+            scope_key: key.to_string(),
+            response: fetched
+                .filter(|_| tier != EmoteTier::User)
+                .map(|fetched| fetched.response.clone())
+                .unwrap_or_default(),
+            sets: fetched
+                .map(|fetched| fetched.sets.clone())
+                .unwrap_or_default(),
+            missing: fetched.is_none(),
+            fetched_at: Utc::now().timestamp_millis(),
+        });
+    }
+}
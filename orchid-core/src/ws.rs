@@ -8,7 +8,11 @@ use axum::{
 };
 use axum_extra::TypedHeader;
 use futures::{sink::SinkExt, stream::StreamExt};
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    sync::Arc,
+};
 use tokio::sync::{mpsc, Mutex};
 use tracing::{debug, error, info};
 
@@ -16,7 +20,11 @@ use uuid::Uuid;
 
 use crate::{
     err::{OrchidError, OrchidResult},
-    twitch::chat::manager::SubscriptionManager,
+    twitch::chat::{
+        backfill::RecentMessages,
+        manager::{feed_label, SubscriptionManager},
+        message::TwitchChatMessage,
+    },
 };
 
 pub struct WebsocketInfo {
@@ -30,14 +38,71 @@ pub struct WebsocketCollection {
     /// Map of usernames to socket infos
     users: HashMap<String, Vec<WebsocketInfo>>,
     sub_manager: Arc<Mutex<SubscriptionManager>>,
+    recent_messages: Arc<Mutex<RecentMessages>>,
+    /// Client id -> sender to its socket, for sending without locking its state
+    senders: HashMap<String, mpsc::Sender<WsMessage>>,
+    /// Client id -> chat messages recently sent to it, so backfill doesn't repeat them
+    delivered: std::sync::Mutex<HashMap<String, DeliveredMessages>>,
+}
+
+/// How many chat message IDs are remembered per client
+const DELIVERED_MEMORY: usize = 1000;
+
+/// The IDs of the last chat messages sent to a client
+#[derive(Default)]
+struct DeliveredMessages {
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+}
+
+impl DeliveredMessages {
+    /// Remembers a message. Returns false if it was already delivered.
+    fn insert(&mut self, message_id: &str) -> bool {
+        if !self.ids.insert(message_id.to_string()) {
+            return false;
+        }
+        self.order.push_back(message_id.to_string());
+        if self.order.len() > DELIVERED_MEMORY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// Recent messages for a client, sent after every lock is released so a slow client can't hold others up
+#[derive(Default)]
+pub struct Backfill {
+    tx: Option<mpsc::Sender<WsMessage>>,
+    messages: Vec<WsMessage>,
+}
+
+impl Backfill {
+    pub async fn send(self) {
+        let Some(tx) = self.tx else {
+            return;
+        };
+        for message in self.messages {
+            if tx.send(message).await.is_err() {
+                break;
+            }
+        }
+    }
 }
 
 impl WebsocketCollection {
-    pub fn new(sub_manager: Arc<Mutex<SubscriptionManager>>) -> Self {
+    pub fn new(
+        sub_manager: Arc<Mutex<SubscriptionManager>>,
+        recent_messages: Arc<Mutex<RecentMessages>>,
+    ) -> Self {
         Self {
             ws: HashMap::new(),
             users: HashMap::new(),
             sub_manager,
+            recent_messages,
+            senders: HashMap::new(),
+            delivered: std::sync::Mutex::new(HashMap::new()),
         }
     }
 
@@ -46,6 +111,7 @@ impl WebsocketCollection {
         username: &str,
         client_id: &str,
         ws: Arc<Option<Mutex<WebsocketState>>>,
+        tx: mpsc::Sender<WsMessage>,
     ) {
         debug!(
             "Adding new handler - username: {}, client_id: {}",
@@ -56,6 +122,7 @@ impl WebsocketCollection {
             self.ws.len()
         );
         self.ws.insert(client_id.to_string(), ws);
+        self.senders.insert(client_id.to_string(), tx);
         self.users
             .entry(username.to_string())
             .or_default()
@@ -136,6 +203,165 @@ impl WebsocketCollection {
         }
     }
 
+    /// Remembers that a chat message went out to some clients, or to every client if they include `global`
+    pub fn mark_delivered<'a>(
+        &self,
+        client_ids: impl IntoIterator<Item = &'a String>,
+        message_id: &str,
+    ) {
+        let mut delivered = self.delivered.lock().unwrap();
+        let client_ids: Vec<&String> = client_ids.into_iter().collect();
+        let client_ids: Vec<&String> = if client_ids.iter().any(|id| *id == "global") {
+            self.ws.keys().collect()
+        } else {
+            client_ids
+        };
+        for client_id in client_ids {
+            delivered
+                .entry(client_id.clone())
+                .or_default()
+                .insert(message_id);
+        }
+    }
+
+    /// Prepares a client's backfill, leaving out messages it already got
+    fn backfill(&self, client_id: &str, messages: Vec<TwitchChatMessage>) -> Backfill {
+        let Some(tx) = self.senders.get(client_id).cloned() else {
+            return Backfill::default();
+        };
+        let mut delivered = self.delivered.lock().unwrap();
+        let delivered = delivered.entry(client_id.to_string()).or_default();
+        let messages = messages
+            .into_iter()
+            .filter(|msg| delivered.insert(&msg.message_id))
+            .map(|msg| WsMessage::Text(serde_json::to_string(&msg).unwrap()))
+            .collect();
+        Backfill {
+            tx: Some(tx),
+            messages,
+        }
+    }
+
+    /// A channel's recent messages, oldest first
+    async fn recent_channel(&self, channel: &str) -> Vec<TwitchChatMessage> {
+        self.recent_messages.lock().await.recent(channel)
+    }
+
+    /// A feed's recent messages, labelled and in time order
+    async fn recent_feed(&self, feed: &str) -> Vec<TwitchChatMessage> {
+        let feed = self.sub_manager.lock().await.get_feed(feed).cloned();
+        let Some(feed) = feed else {
+            return vec![];
+        };
+
+        let mut messages = vec![];
+        {
+            let recent = self.recent_messages.lock().await;
+            for channel in &feed.channels {
+                let label = feed_label(&feed, &channel.channel);
+                messages.extend(recent.recent(&channel.channel).into_iter().map(|mut msg| {
+                    msg.feed = label.clone();
+                    msg
+                }));
+            }
+        }
+        messages.sort_by(|a, b| a.server_timestamp.cmp(&b.server_timestamp));
+        messages
+    }
+
+    /// Prepares a channel's recent messages for every connected client, after a global subscription
+    pub async fn backfill_channel_globally(&self, channel: &str) -> Vec<Backfill> {
+        let messages = self.recent_channel(channel).await;
+        self.ws
+            .keys()
+            .map(|client_id| self.backfill(client_id, messages.clone()))
+            .collect()
+    }
+
+    /// Prepares a feed's recent messages for every connected client, after a global feed subscription
+    pub async fn backfill_feed_globally(&self, feed: &str) -> Vec<Backfill> {
+        let messages = self.recent_feed(feed).await;
+        self.ws
+            .keys()
+            .map(|client_id| self.backfill(client_id, messages.clone()))
+            .collect()
+    }
+
+    /// Prepares the recent messages of everything subscribed to globally, for a client that just connected
+    pub async fn backfill_global(&self, client_id: &str) -> Backfill {
+        let (channels, feeds) = {
+            let mgr = self.sub_manager.lock().await;
+            (
+                mgr.get_client_subscriptions("global"),
+                mgr.get_client_feeds("global"),
+            )
+        };
+        let mut messages = vec![];
+        for channel in channels {
+            messages.extend(self.recent_channel(&channel).await);
+        }
+        for feed in feeds {
+            messages.extend(self.recent_feed(&feed).await);
+        }
+        self.backfill(client_id, messages)
+    }
+
+    /// Handles a subscription command sent by a client:
+    /// `subscribe <channel>`, `unsubscribe <channel>`, `subscribe_feed <feed>` or `unsubscribe_feed <feed>`.
+    /// Returns the client's backfill, to send once the collection is unlocked,
+    /// or `None` if the text isn't a subscription command.
+    pub async fn handle_subscription_command(
+        &self,
+        client_id: &str,
+        text: &str,
+    ) -> OrchidResult<Option<Backfill>> {
+        let Some((command, target)) = text.trim().split_once(' ') else {
+            return Ok(None);
+        };
+        let target = target.trim();
+
+        let messages = match command {
+            "subscribe" => {
+                let channel = target.to_lowercase();
+                self.sub_manager
+                    .lock()
+                    .await
+                    .subscribe(channel.clone(), client_id.to_string())
+                    .await
+                    .map_err(|e| OrchidError::ChannelError(e.to_string()))?;
+                self.recent_channel(&channel).await
+            }
+            "unsubscribe" => {
+                let channel = target.to_lowercase();
+                self.sub_manager
+                    .lock()
+                    .await
+                    .unsubscribe(&channel, client_id)
+                    .await;
+                vec![]
+            }
+            "subscribe_feed" => {
+                self.sub_manager
+                    .lock()
+                    .await
+                    .subscribe_feed(target, client_id.to_string())
+                    .await?;
+                self.recent_feed(target).await
+            }
+            "unsubscribe_feed" => {
+                self.sub_manager
+                    .lock()
+                    .await
+                    .unsubscribe_feed(target, client_id)
+                    .await;
+                vec![]
+            }
+            _ => return Ok(None),
+        };
+        // Messages the client already has, e.g. from subscribing before, are left out
+        Ok(Some(self.backfill(client_id, messages)))
+    }
+
     pub async fn remove_handler(&mut self, username: String) {
         debug!("Removing handler for {}", username);
 
@@ -144,6 +370,8 @@ impl WebsocketCollection {
             for info in infos {
                 // Remove from websocket connections
                 self.ws.remove(&info.client_id);
+                self.senders.remove(&info.client_id);
+                self.delivered.lock().unwrap().remove(&info.client_id);
 
                 // Remove all subscriptions for this client
                 self.sub_manager.lock().await.remove_client(&info.client_id);
@@ -215,7 +443,7 @@ impl WebsocketHandler {
         // Create a channel for sending messages to the websocket
         let (tx, rx) = mpsc::channel::<WsMessage>(100);
 
-        let state = Arc::new(Some(Mutex::new(WebsocketState { tx })));
+        let state = Arc::new(Some(Mutex::new(WebsocketState { tx: tx.clone() })));
 
         // create a random uid for the client
         let client_id = Uuid::new_v4();
@@ -227,11 +455,13 @@ impl WebsocketHandler {
 
         {
             let mut collection = ws_collection.lock().await;
-            collection.add_handler(&username, &client_id.to_string(), state.clone());
+            collection.add_handler(&username, &client_id.to_string(), state.clone(), tx);
         }
 
-        self.ws
-            .on_upgrade(move |socket| handle_socket(socket, addr, rx, username, ws_collection))
+        let client_id = client_id.to_string();
+        self.ws.on_upgrade(move |socket| {
+            handle_socket(socket, addr, rx, username, client_id, ws_collection)
+        })
     }
 }
 
@@ -240,6 +470,7 @@ async fn handle_socket(
     addr: SocketAddr,
     mut rx: mpsc::Receiver<WsMessage>,
     ws_name: String,
+    client_id: String,
     ws_collection: Arc<Mutex<WebsocketCollection>>,
 ) {
     let (mut sender, mut receiver) = socket.split();
@@ -259,6 +490,10 @@ async fn handle_socket(
         }
     });
 
+    // Catch the client up on chat it's subscribed to through global subscriptions
+    let backfill = ws_collection.lock().await.backfill_global(&client_id).await;
+    backfill.send().await;
+
     // Task for receiving messages
     // Copying a few things to avoid borrowing issues
     let ws_name_cpy = ws_name.clone();
@@ -277,6 +512,17 @@ async fn handle_socket(
                             .await
                             .broadcast_message_to_user(&ws_name_cpy, WsMessage::Text(echo))
                             .await;
+                    } else {
+                        let result = ws_collection_cpy
+                            .lock()
+                            .await
+                            .handle_subscription_command(&client_id, &text)
+                            .await;
+                        match result {
+                            Ok(Some(backfill)) => backfill.send().await,
+                            Ok(None) => {}
+                            Err(e) => error!("Subscription command from {} failed: {:?}", addr, e),
+                        }
                     }
                 }
                 Message::Binary(data) => {
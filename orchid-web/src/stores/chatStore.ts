@@ -12,10 +12,132 @@ export interface TwitchChatMessage {
   // User's current badges (name, URL)
   userBadges: [string, string][];
   nicknameColor: [number, number, number];
+  // The name color the user picked on Twitch, before readability correction
+  originalColor: [number, number, number] | null;
   // The message, with Discord-esque emote formatting
   message: string;
   messageId: string;
   serverTimestamp: string;
+  // Where the message originally came from, if relayed through Shared Chat
+  sharedChat: SharedChatOrigin | null;
+  // The merged feed this message was delivered through, if any
+  feed: FeedLabel | null;
+  // Names of the chat filters that tagged this message
+  filterTags: string[];
+  // Whether this is the user's first message in the channel
+  firstMessage: boolean;
+  // Whether Twitch considers the user a returning chatter
+  returningChatter: boolean;
+  // Whether orchid has not seen the user chat in the channel before
+  newChatter: boolean;
+  // Highlight rules this message matched
+  highlights: MessageHighlight[];
+  // @mentions in the message, with UTF-16 offsets into `message`
+  mentions: MessageMention[];
+  // Emotes in the message, with modifier and zero-width emotes stacked on the emote before them
+  emotes: MessageEmote[];
+}
+
+export type EmoteEffect =
+  | "zeroWidth"
+  | "hidden"
+  | "flipX"
+  | "flipY"
+  | "growX"
+  | "slide"
+  | "rainbow"
+  | "hyperRed"
+  | "hyperShake"
+  | "cursed"
+  | "jam"
+  | "bounce";
+
+export interface Emote {
+  source: string;
+  id: string;
+  name: string;
+  channel: string;
+  effect: number | null;
+  // The default image at each scale, smallest first
+  url: string[];
+  // Every image the provider has for the emote, by scale and format
+  images: EmoteImages;
+  // `effect`, decoded
+  effects: EmoteEffect[];
+  // Whether the emote is drawn over or transforms the emote before it
+  modifier: boolean;
+}
+
+export interface EmoteImages {
+  animated: boolean;
+  // Smallest scale first
+  scales: EmoteImageScale[];
+}
+
+export interface EmoteImageScale {
+  // 1 for 1x, 2 for 2x and so on
+  scale: number;
+  // The image to use by default, animated if the emote is
+  url: string;
+  // Size in pixels, if the provider gives it
+  width: number | null;
+  height: number | null;
+  formats: EmoteImageFormat[];
+}
+
+export interface EmoteImageFormat {
+  // "webp", "avif", "gif" or "png"
+  format: string;
+  animated: boolean;
+  url: string;
+}
+
+export interface MessageEmote extends Emote {
+  // Which whitespace-separated word of the original message it is
+  word: number;
+  // Modifier and zero-width emotes applied to this one, in order
+  modifiers: Emote[];
+}
+
+export interface MessageMention {
+  start: number;
+  end: number;
+  login: string;
+  userId: string | null;
+  displayName: string | null;
+  color: [number, number, number];
+}
+
+export interface MessageHighlight {
+  ruleId: number;
+  style: HighlightStyle;
+}
+
+export interface HighlightStyle {
+  color: [number, number, number] | null;
+  background: [number, number, number] | null;
+  label: string | null;
+}
+
+export interface FeedLabel {
+  // The feed name
+  feed: string;
+  // Label for the channel the message was sent in
+  label: string;
+  color: [number, number, number];
+}
+
+export interface SharedChatOrigin {
+  // The channel ID the message was originally sent in
+  roomId: string;
+  // The channel name the message was originally sent in, if known
+  roomLogin: string | null;
+  // The message ID in the originating channel
+  messageId: string;
+  // User's badges in the originating channel (name, version)
+  badges: [string, string][];
+  // Whether the message was only sent to the originating channel
+  sourceOnly: boolean;
 }
 
 export interface TwitchChatUser {